@@ -1,194 +1,456 @@
-use std::mem;
-use std::ops::Deref;
+use std::borrow::Cow;
+use std::fmt;
 use std::rc::Rc;
+use unicode_segmentation::UnicodeSegmentation as _;
 
 use crate::line::*;
+use crate::rope::*;
 
 pub struct Buffer {
-	lines: Vec <Line>,
-	line_idx: usize,
-	char_idx: usize,
+	lines: Rope,
+	cursor: Position,
+}
+
+/// Place in a buffer, as a line and a byte index into it
+#[ derive (Clone, Copy, Debug, Default, Eq, Hash, Ord, PartialEq, PartialOrd) ]
+pub struct Position {
+	pub line_idx: usize,
+	pub char_idx: usize,
+}
+
+/// Text between two positions, with the start never after the end
+#[ derive (Clone, Copy, Debug, Default, Eq, Hash, PartialEq) ]
+pub struct Range {
+	pub start: Position,
+	pub end: Position,
+}
+
+impl Position {
+
+	pub fn new (line_idx: usize, char_idx: usize) -> Self {
+		Self { line_idx, char_idx }
+	}
+
+	/// Where this position ends up after a range is replaced with text ending at `new_end`
+	pub fn adjust (self, range: Range, new_end: Self) -> Self {
+		if self <= range.start { return self }
+		if self < range.end { return range.start }
+		if self.line_idx == range.end.line_idx {
+			Self::new (new_end.line_idx, self.char_idx - range.end.char_idx + new_end.char_idx)
+		} else {
+			Self::new (self.line_idx - range.end.line_idx + new_end.line_idx, self.char_idx)
+		}
+	}
+
+}
+
+impl Range {
+
+	/// Range between two positions, in either order
+	pub fn new (pos_0: Position, pos_1: Position) -> Self {
+		Self { start: pos_0.min (pos_1), end: pos_0.max (pos_1) }
+	}
+
+	/// Empty range at a position, for inserting text
+	pub fn at (pos: Position) -> Self {
+		Self { start: pos, end: pos }
+	}
+
+	pub fn contains (& self, pos: Position) -> bool {
+		self.start <= pos && pos < self.end
+	}
+
+}
+
+/// Sequence which separates lines in a file, which is removed from the lines in a buffer
+#[ derive (Clone, Copy, Debug, Eq, PartialEq) ]
+pub enum LineEnding {
+	Lf,
+	Crlf,
+	Cr,
+}
+
+impl LineEnding {
+
+	/// Most common line ending in some data, LF if there are none, and whether others are used too
+	pub fn detect (data: & [u8]) -> (Self, bool) {
+		let (mut lf, mut crlf, mut cr) = (0, 0, 0);
+		let mut bytes = data.iter ().peekable ();
+		while let Some (& by) = bytes.next () {
+			match by {
+				b'\n' => lf += 1,
+				b'\r' if bytes.peek () == Some (&& b'\n') => { bytes.next (); crlf += 1 },
+				b'\r' => cr += 1,
+				_ => (),
+			}
+		}
+		let mixed = 1 < [ lf, crlf, cr ].iter ().filter (|& & num| 0 < num).count ();
+		if lf < crlf && cr < crlf { (Self::Crlf, mixed) }
+		else if lf < cr && crlf < cr { (Self::Cr, mixed) }
+		else { (Self::Lf, mixed) }
+	}
+
+	pub fn parse (name: & str) -> Option <Self> {
+		match name.trim ().to_ascii_lowercase ().as_str () {
+			"lf" | "unix" => Some (Self::Lf),
+			"crlf" | "dos" => Some (Self::Crlf),
+			"cr" | "mac" => Some (Self::Cr),
+			_ => None,
+		}
+	}
+
+	pub fn as_str (self) -> & 'static str {
+		match self {
+			Self::Lf => "\n",
+			Self::Crlf => "\r\n",
+			Self::Cr => "\r",
+		}
+	}
+
+	/// Line ending which starts at an offset in some data, if there is one
+	pub fn at (data: & [u8], pos: usize) -> Option <Self> {
+		match (data.get (pos), data.get (pos + 1)) {
+			(Some (b'\r'), Some (b'\n')) => Some (Self::Crlf),
+			(Some (b'\r'), _) => Some (Self::Cr),
+			(Some (b'\n'), _) => Some (Self::Lf),
+			_ => None,
+		}
+	}
+
+	/// Start of the next line ending of any kind in some data, from the given offset
+	pub fn find (data: & [u8], from: usize) -> Option <(usize, Self)> {
+		let pos = from + data [from .. ].iter ().position (|& by| by == b'\n' || by == b'\r') ?;
+		Some ((pos, Self::at (data, pos) ?))
+	}
+
+	/// Replace every kind of line ending in some text with `'\n'`, as used inside a buffer
+	pub fn normalise (text: & str) -> Cow <'_, str> {
+		if ! text.contains ('\r') { return Cow::Borrowed (text) }
+		Cow::Owned (text.replace ("\r\n", "\n").replace ('\r', "\n"))
+	}
+
+}
+
+impl fmt::Display for LineEnding {
+	fn fmt (& self, formatter: & mut fmt::Formatter) -> fmt::Result {
+		formatter.write_str (match self {
+			Self::Lf => "LF",
+			Self::Crlf => "CRLF",
+			Self::Cr => "CR",
+		})
+	}
 }
 
 impl Buffer {
 
+	/// Split text into lines at any line ending, sharing it between them
+	pub fn split (src: & Rc <String>) -> Self {
+		let mut prev = 0;
+		let mut lines = Vec::new ();
+		while let Some ((next, ending)) = LineEnding::find (src.as_bytes (), prev) {
+			lines.push (Line::Shared (src.clone (), prev, next));
+			prev = next + ending.as_str ().len ();
+		}
+		if prev < src.len () || lines.is_empty () {
+			lines.push (Line::Shared (src.clone (), prev, src.len ()));
+		}
+		Self { lines: Rope::from_lines (lines), cursor: Position::default () }
+	}
+
 	pub fn line_idx (& self) -> usize {
-		self.line_idx
+		self.cursor.line_idx
+	}
+
+	pub fn cursor (& self) -> Position {
+		self.cursor
 	}
 
-	pub fn char_idx (& self) -> usize {
-		self.char_idx
+	/// Position after the last character
+	pub fn end (& self) -> Position {
+		let line_idx = self.lines.len () - 1;
+		Position::new (line_idx, self.line_at (line_idx).len ())
 	}
 
 	pub fn num_lines (& self) -> usize {
 		self.lines.len ()
 	}
 
+	/// Text of any line, without its newline
+	pub fn line_at (& self, line_idx: usize) -> & str {
+		self.lines.line (line_idx).as_str ()
+	}
+
+	/// Text of each line, starting from the given one
+	pub fn lines_from (& self, line_idx: usize) -> impl Iterator <Item = & str> {
+		self.lines.iter_from (line_idx).map (Line::as_str)
+	}
+
+	/// Byte offset of a position, counting one byte for each newline
+	pub fn offset (& self, pos: Position) -> usize {
+		self.lines.line_to_offset (pos.line_idx) + pos.char_idx
+	}
+
+	/// Position of a byte offset, which is moved back to a char boundary if needed
+	pub fn position (& self, offset: usize) -> Position {
+		let offset = offset.min (self.num_bytes ());
+		let (line_idx, mut char_idx) = self.lines.offset_to_line (offset);
+		let line = self.line_at (line_idx);
+		while ! line.is_char_boundary (char_idx) { char_idx -= 1; }
+		Position::new (line_idx, char_idx)
+	}
+
+	/// Char offset of a position, counting one char for each newline
+	pub fn char_offset (& self, pos: Position) -> usize {
+		self.lines.line_to_char (pos.line_idx)
+			+ self.line_at (pos.line_idx) [ .. pos.char_idx].chars ().count ()
+	}
+
+	/// Position of a char offset, which is moved back to the start of a grapheme cluster
+	pub fn char_position (& self, char_offset: usize) -> Position {
+		let (line_idx, num_chars) = self.lines.char_to_line (char_offset);
+		let line = self.line_at (line_idx);
+		let char_idx = line.char_indices ().nth (num_chars).map_or (line.len (), |(idx, _)| idx);
+		let char_idx = line.grapheme_indices (true)
+			.map (|(idx, _)| idx)
+			.chain ([line.len ()])
+			.take_while (|& idx| idx <= char_idx)
+			.last ()
+			.unwrap_or (0);
+		Position::new (line_idx, char_idx)
+	}
+
+	/// Text in a range, with newlines between lines
+	pub fn text (& self, range: Range) -> String {
+		let Range { start, end } = range;
+		if start.line_idx == end.line_idx {
+			return self.line_at (start.line_idx) [start.char_idx .. end.char_idx].to_owned ();
+		}
+		let mut result = self.line_at (start.line_idx) [start.char_idx .. ].to_owned ();
+		for line in self.lines_from (start.line_idx + 1).take (end.line_idx - start.line_idx - 1) {
+			result.push ('\n');
+			result.push_str (line);
+		}
+		result.push ('\n');
+		result.push_str (& self.line_at (end.line_idx) [ .. end.char_idx]);
+		result
+	}
+
+	/// Replace the text in a range, keeping the cursor's place, and return where the new text ends
+	pub fn replace (& mut self, range: Range, text: & str) -> Position {
+		let Range { start, end } = range;
+		debug_assert! (end <= self.end ());
+		debug_assert! (self.line_at (start.line_idx).is_char_boundary (start.char_idx));
+		debug_assert! (self.line_at (end.line_idx).is_char_boundary (end.char_idx));
+		let new_end = if start.line_idx == end.line_idx && ! text.contains ('\n') {
+			self.lines.edit (start.line_idx, |line| line.replace_range (start.char_idx .. end.char_idx, text));
+			Position::new (start.line_idx, start.char_idx + text.len ())
+		} else {
+			let head = & self.line_at (start.line_idx) [ .. start.char_idx];
+			let tail = & self.line_at (end.line_idx) [end.char_idx .. ];
+			let mut parts: Vec <& str> = text.split ('\n').collect ();
+			let last = parts.pop ().unwrap ();
+			let new_end = Position::new (
+				start.line_idx + parts.len (),
+				if parts.is_empty () { head.len () } else { 0 } + last.len ());
+			let mut new_lines: Vec <Line> = Vec::with_capacity (parts.len () + 1);
+			let mut parts = parts.into_iter ();
+			if let Some (first) = parts.next () {
+				new_lines.push (Line::Owned (format! ("{head}{first}")));
+				new_lines.extend (parts.map (Line::from));
+				new_lines.push (Line::Owned (format! ("{last}{tail}")));
+			} else {
+				new_lines.push (Line::Owned (format! ("{head}{last}{tail}")));
+			}
+			self.lines.splice (start.line_idx .. end.line_idx + 1, new_lines);
+			new_end
+		};
+		self.cursor = self.cursor.adjust (range, new_end);
+		new_end
+	}
+
+	/// Remove the text in a range and return it
+	pub fn cut (& mut self, range: Range) -> String {
+		let text = self.text (range);
+		self.replace (range, "");
+		text
+	}
+
+	/// Add lines to the end, such as those found while loading in the background
+	pub fn append_lines (& mut self, lines: Vec <Line>) {
+		let num_lines = self.lines.len ();
+		self.lines.splice (num_lines .. num_lines, lines);
+	}
+
+	/// Copy any lines which refer to a mapped file of the given size, returning false if any are invalid
+	pub fn unmap (& mut self, size: usize) -> bool {
+		let mut valid = true;
+		self.lines.edit_all (|line| valid &= line.unmap (size));
+		let line = self.line_at (self.cursor.line_idx);
+		let mut char_idx = self.cursor.char_idx.min (line.len ());
+		while ! line.is_char_boundary (char_idx) { char_idx -= 1; }
+		self.cursor.char_idx = char_idx;
+		valid
+	}
+
+	/// Total size of the text in bytes
+	pub fn num_bytes (& self) -> usize {
+		self.lines.summary ().bytes - 1
+	}
+
 	pub fn move_to (& mut self, line_idx: usize, char_idx: usize) {
-		debug_assert! (line_idx < self.lines.len ());
-		debug_assert! (char_idx <= self.lines [line_idx].len ());
-		debug_assert! (self.lines [line_idx].is_char_boundary (char_idx));
-		self.line_idx = line_idx;
-		self.char_idx = char_idx;
+		self.set_cursor (Position::new (line_idx, char_idx));
+	}
+
+	pub fn set_cursor (& mut self, pos: Position) {
+		debug_assert! (pos.line_idx < self.lines.len ());
+		debug_assert! (pos.char_idx <= self.line_at (pos.line_idx).len ());
+		debug_assert! (self.line_at (pos.line_idx).is_char_boundary (pos.char_idx));
+		self.cursor = pos;
 	}
 
 	pub fn move_left (& mut self, mut num: usize) {
-		while self.char_idx < num {
-			if self.line_idx == 0 {
-				self.char_idx = 0;
+		loop {
+			let num_left = self.line_left ().graphemes (true).count ();
+			if num <= num_left { break }
+			if self.cursor.line_idx == 0 {
+				self.cursor.char_idx = 0;
 				return;
 			}
-			num -= self.line_left ().chars ().count () + 1;
-			self.line_idx -= 1;
-			self.char_idx = self.line ().len ();
+			num -= num_left + 1;
+			self.cursor.line_idx -= 1;
+			self.cursor.char_idx = self.line ().len ();
 		}
-		self.char_idx -=
-			self.line_left ().chars ().rev ()
+		self.cursor.char_idx -=
+			self.line_left ().graphemes (true).rev ()
 				.take (num)
-				.map (char::len_utf8)
+				.map (str::len)
 				.sum::<usize> ();
 	}
 
 	pub fn move_right (& mut self, mut num: usize) {
-		while self.line ().len () < self.char_idx + num {
-			if self.line_idx + 1 == self.lines.len () {
-				self.char_idx = self.line ().len ();
+		loop {
+			let num_right = self.line_right ().graphemes (true).count ();
+			if num <= num_right { break }
+			if self.cursor.line_idx + 1 == self.lines.len () {
+				self.cursor.char_idx = self.line ().len ();
 				return;
 			}
-			num -= self.line_right ().chars ().count () + 1;
-			self.line_idx += 1;
-			self.char_idx = 0;
+			num -= num_right + 1;
+			self.cursor.line_idx += 1;
+			self.cursor.char_idx = 0;
 		}
-		self.char_idx +=
-			self.line_right ().chars ()
+		self.cursor.char_idx +=
+			self.line_right ().graphemes (true)
 				.take (num)
-				.map (char::len_utf8)
+				.map (str::len)
 				.sum::<usize> ();
 	}
 
 	pub fn insert_char (& mut self, ch: char) {
-		if ch == '\n' {
-			let char_idx = self.char_idx;
-			let line_0 = Line::Owned (self.line () [ .. char_idx].to_owned ());
-			let line_1 = Line::Owned (self.line () [char_idx .. ].to_owned ());
-			self.lines.splice (self.line_idx .. self.line_idx + 1, [ line_0, line_1 ]);
-			self.line_idx += 1;
-			self.char_idx = 0;
-		} else {
-			let char_idx = self.char_idx;
-			self.line_mut ().insert (char_idx, ch);
-			self.char_idx += ch.len_utf8 ();
-		}
+		self.insert_str (ch.encode_utf8 (& mut [0; 4]));
 	}
 
 	pub fn insert_str (& mut self, src: & str) {
-		for ch in src.chars () {
-			self.insert_char (ch);
-		}
+		let new_end = self.replace (Range::at (self.cursor ()), src);
+		self.set_cursor (new_end);
 	}
 
 	pub fn line (& self) -> & str {
-		 self.lines [self.line_idx].as_str ()
+		self.line_at (self.cursor.line_idx)
 	}
 
 	pub fn line_left (& self) -> & str {
-		& self.lines [self.line_idx].as_str () [ .. self.char_idx]
+		& self.line () [ .. self.cursor.char_idx]
 	}
 
 	pub fn line_right (& self) -> & str {
-		& self.lines [self.line_idx].as_str () [self.char_idx .. ]
+		& self.line () [self.cursor.char_idx .. ]
 	}
 
-	fn line_mut (& mut self) -> & mut String {
-		 self.lines [self.line_idx].make_mut ()
-	}
-
-	pub fn delete_char_left (& mut self) -> Option <char> {
-		if 0 < self.char_idx {
-			let ch = self.line_left ().chars ().next_back ().unwrap ();
-			self.char_idx -= ch.len_utf8 ();
-			let char_idx = self.char_idx;
-			Some (self.line_mut ().remove (char_idx))
-		} else if 0 < self.line_idx {
-			self.line_idx -= 1;
-			self.char_idx = self.line ().len ();
-			let line = self.lines.remove (self.line_idx + 1);
-			self.line_mut ().push_str (& line);
-			Some ('\n')
+	/// Position of the char before a position, which may be the end of the previous line
+	fn prev_char (& self, pos: Position) -> Option <Position> {
+		if 0 < pos.char_idx {
+			let ch = self.line_at (pos.line_idx) [ .. pos.char_idx].chars ().next_back ().unwrap ();
+			Some (Position::new (pos.line_idx, pos.char_idx - ch.len_utf8 ()))
+		} else if 0 < pos.line_idx {
+			Some (Position::new (pos.line_idx - 1, self.line_at (pos.line_idx - 1).len ()))
 		} else {
 			None
 		}
 	}
 
-	pub fn delete_char_right (& mut self) -> Option <char> {
-		if self.char_idx < self.line ().len () {
-			let char_idx = self.char_idx;
-			Some (self.line_mut ().remove (char_idx))
-		} else if self.line_idx + 1 < self.lines.len () {
-			let line = self.lines.remove (self.line_idx + 1);
-			self.line_mut ().push_str (& line);
-			Some ('\n')
+	/// Position after the char at a position, which may be the start of the next line
+	fn next_char (& self, pos: Position) -> Option <Position> {
+		let line = self.line_at (pos.line_idx);
+		if let Some (ch) = line [pos.char_idx .. ].chars ().next () {
+			Some (Position::new (pos.line_idx, pos.char_idx + ch.len_utf8 ()))
+		} else if pos.line_idx + 1 < self.lines.len () {
+			Some (Position::new (pos.line_idx + 1, 0))
 		} else {
 			None
 		}
 	}
 
-	pub fn cut_line (& mut self) -> String {
-		if self.line_idx + 1 < self.lines.len () {
-			let mut result = self.lines.remove (self.line_idx).to_owned ();
-			self.char_idx = 0;
-			result.push ('\n');
-			result
+	/// Position before the grapheme cluster before a position, like [`Self::prev_char`]
+	fn prev_cluster (& self, pos: Position) -> Option <Position> {
+		if 0 < pos.char_idx {
+			let cluster = self.line_at (pos.line_idx) [ .. pos.char_idx].graphemes (true).next_back ().unwrap ();
+			Some (Position::new (pos.line_idx, pos.char_idx - cluster.len ()))
 		} else {
-			mem::replace (self.line_mut (), String::new ())
+			self.prev_char (pos)
 		}
 	}
 
-	pub fn cut_bytes_right (& mut self, num_bytes: usize) -> String {
-
-		// handle single line
-
-		let rest = & self.line_right ();
-		if num_bytes <= rest.len () {
-			let result = rest [ .. num_bytes].to_string ();
-			let char_idx = self.char_idx;
-			self.line_mut ().replace_range (char_idx .. char_idx + num_bytes, "");
-			return result;
+	/// Position after the grapheme cluster at a position, like [`Self::next_char`]
+	fn next_cluster (& self, pos: Position) -> Option <Position> {
+		match self.line_at (pos.line_idx) [pos.char_idx .. ].graphemes (true).next () {
+			Some (cluster) => Some (Position::new (pos.line_idx, pos.char_idx + cluster.len ())),
+			None => self.next_char (pos),
 		}
+	}
 
-		// handle multi-line
+	/// Char at a position, which is a newline at the end of a line
+	fn char_at (& self, pos: Position) -> char {
+		self.line_at (pos.line_idx) [pos.char_idx .. ].chars ().next ().unwrap_or ('\n')
+	}
 
-		let mut result = String::with_capacity (num_bytes);
-		result.push_str (rest);
-		result.push ('\n');
-		let mut rem_bytes = num_bytes - rest.len () - 1;
-		let mut line_idx = self.line_idx + 1;
-		while self.lines [line_idx].len () < rem_bytes {
-			result.push_str (self.lines [line_idx].as_str ());
-			result.push ('\n');
-			rem_bytes -= self.lines [line_idx].len () + 1;
-			line_idx += 1;
-		}
-		result.push_str (& self.lines [line_idx] [ .. rem_bytes]);
-		let temp = self.lines [line_idx] [rem_bytes .. ].to_string ();
-		let char_idx = self.char_idx;
-		self.line_mut ().truncate (char_idx);
-		self.line_mut ().push_str (& temp);
-		self.lines.splice (self.line_idx + 1 .. line_idx + 1, []);
-		debug_assert_eq! (num_bytes, result.len ());
+	/// Position after the end of the next word, judging each grapheme cluster by its first char
+	pub fn next_word (& self, mut pos: Position, is_word: impl Fn (char) -> bool) -> Position {
+		let mut in_word = false;
+		while let Some (next) = self.next_cluster (pos) {
+			let ch = self.char_at (pos);
+			if is_word (ch) { in_word = true } else if in_word { break }
+			pos = next;
+		}
+		pos
+	}
 
-		result
+	/// Position of the start of the previous word
+	pub fn prev_word (& self, mut pos: Position, is_word: impl Fn (char) -> bool) -> Position {
+		let mut in_word = false;
+		while let Some (prev) = self.prev_cluster (pos) {
+			let ch = self.char_at (prev);
+			if is_word (ch) { in_word = true } else if in_word { break }
+			pos = prev;
+		}
+		pos
+	}
 
+	pub fn delete_char_left (& mut self) -> Option <char> {
+		let start = self.prev_char (self.cursor ()) ?;
+		self.cut (Range::new (start, self.cursor ())).chars ().next ()
 	}
 
-	pub fn peek_left (& self) -> Option <char> {
-		self.line_left ().chars ().next_back ()
-			.or ((0 < self.line_idx).then_some ('\n'))
+	pub fn delete_char_right (& mut self) -> Option <char> {
+		let end = self.next_char (self.cursor ()) ?;
+		self.cut (Range::new (self.cursor (), end)).chars ().next ()
 	}
 
-	pub fn peek_right (& self) -> Option <char> {
-		self.line_right ().chars ().next ()
-			.or ((self.line_idx < self.lines.len ()).then_some ('\n'))
+	pub fn cut_bytes_right (& mut self, num_bytes: usize) -> String {
+		let end = self.position (self.offset (self.cursor ()) + num_bytes);
+		let result = self.cut (Range::new (self.cursor (), end));
+		debug_assert_eq! (num_bytes, result.len ());
+		result
 	}
 
 }
@@ -196,18 +458,16 @@ impl Buffer {
 impl Default for Buffer {
 
 	fn default () -> Self {
-		let lines = vec! [ Line::Owned ("".to_owned ()) ];
-		Self { lines, line_idx: 0, char_idx: 0 }
+		let lines = Rope::from_lines (vec! [ Line::Owned ("".to_owned ()) ]);
+		Self { lines, cursor: Position::default () }
 	}
 
 }
 
-impl Deref for Buffer {
+impl From <Vec <Line>> for Buffer {
 
-	type Target = [Line];
-
-	fn deref (& self) -> & [Line] {
-		& self.lines
+	fn from (lines: Vec <Line>) -> Self {
+		Self { lines: Rope::from_lines (lines), cursor: Position::default () }
 	}
 
 }
@@ -215,15 +475,49 @@ impl Deref for Buffer {
 impl From <& Rc <String>> for Buffer {
 
 	fn from (src: & Rc <String>) -> Self {
-		let mut prev = 0;
-		let mut lines = Vec::new ();
-		while let Some (next) = src [prev .. ].find ('\n') {
-			let next = prev + next;
-			lines.push (Line::Shared (src.clone (), prev, next));
-			prev = next + 1;
-		}
-		lines.push (Line::Shared (src.clone (), prev, src.len ()));
-		Self { lines, line_idx: 0, char_idx: 0 }
+		Self::split (src)
+	}
+
+}
+
+#[ cfg (test) ]
+mod tests {
+
+	use super::*;
+
+	#[ test ]
+	fn replace_keeps_cursor () {
+		let mut buffer = Buffer::from (& Rc::new ("one\ntwo\nthree".to_owned ()));
+		buffer.set_cursor (Position::new (2, 3));
+		let range = Range::new (Position::new (1, 1), Position::new (0, 1));
+		assert_eq! (buffer.text (range), "ne\nt");
+		assert_eq! (buffer.replace (range, "X\nY\nZ"), Position::new (2, 1));
+		assert_eq! (buffer.lines_from (0).collect::<Vec <_>> (), [ "oX", "Y", "Zwo", "three" ]);
+		assert_eq! (buffer.cursor (), Position::new (3, 3));
+		let range = Range::new (Position::new (3, 1), Position::new (3, 2));
+		assert_eq! (buffer.replace (range, "HH"), Position::new (3, 3));
+		assert_eq! (buffer.line_at (3), "tHHree");
+		assert_eq! (buffer.cursor (), Position::new (3, 4));
+		assert_eq! (buffer.cut (Range::new (Position::new (2, 3), Position::new (3, 5))), "\ntHHre");
+		assert_eq! (buffer.cursor (), Position::new (2, 3));
+		assert_eq! (buffer.offset (buffer.cursor ()), 8);
+		assert_eq! (buffer.position (8), buffer.cursor ());
+		assert_eq! (buffer.position (100), buffer.end ());
+		assert_eq! (buffer.char_offset (buffer.cursor ()), 8);
+		assert_eq! (buffer.char_position (8), buffer.cursor ());
+		assert_eq! (buffer.char_position (100), buffer.end ());
+		assert_eq! (buffer.text (Range::new (Position::default (), buffer.end ())), "oX\nY\nZwoe");
+	}
+
+	#[ test ]
+	fn char_offsets () {
+		let buffer = Buffer::from (& Rc::new ("aé\ne\u{301}x\nü".to_owned ()));
+		assert_eq! (buffer.char_offset (Position::new (1, 3)), 5);
+		assert_eq! (buffer.char_position (5), Position::new (1, 3));
+		assert_eq! (buffer.char_position (4), Position::new (1, 0));
+		assert_eq! (buffer.char_position (2), Position::new (0, 3));
+		assert_eq! (buffer.char_offset (buffer.end ()), 8);
+		assert_eq! (buffer.char_position (100), buffer.end ());
 	}
 
 }
@@ -14,17 +14,35 @@ pub struct Config {
 	pub palette: HashMap <Rc <str>, Colour>,
 	pub ui: ConfigUi,
 	//pub colour_schemes: HashMap <Rc <str>, Rc <str>>,
-	//pub file_types_by_extension: HashMap <Rc <str>, Rc <FileType>>,
+	#[ serde (rename = "file-types", default) ]
+	pub file_types: Vec <Rc <FileType>>,
 }
 
 impl Config {
+
 	pub fn load () -> GenResult <Self> {
 		let config_path = format! (
 			"{home}/.config/jtx/config",
 			home = env::var ("HOME").unwrap ());
-		let mut config_str = fs::read_to_string (config_path) ?;
+		let config_str = fs::read_to_string (config_path) ?;
 		Ok (toml::from_str (& config_str) ?)
 	}
+
+	/// Find the first file type which claims a file name, by its extension, or the default one
+	pub fn file_type (& self, filename: & str) -> Option <Rc <FileType>> {
+		let basename = filename.rsplit ('/').next ().unwrap_or (filename);
+		basename.rsplit_once ('.')
+			.and_then (|(_, extension)| self.file_types.iter ()
+				.find (|file_type| file_type.extensions.iter ().any (|ext| & ** ext == extension)))
+			.cloned ()
+			.or_else (|| self.default_file_type ())
+	}
+
+	/// File type for files no other type claims, which is the first one without any extensions
+	pub fn default_file_type (& self) -> Option <Rc <FileType>> {
+		self.file_types.iter ().find (|file_type| file_type.extensions.is_empty ()).cloned ()
+	}
+
 }
 
 #[ derive (Deserialize) ]
@@ -34,16 +52,123 @@ pub struct ConfigUi {
 	pub status: ConfigTextAttr,
 	#[ serde (rename = "line-nums") ]
 	pub line_nums: ConfigTextAttr,
+	#[ serde (default) ]
+	pub selection: Option <ConfigTextAttr>,
+	#[ serde (default) ]
+	pub control: Option <ConfigTextAttr>,
 }
 
 #[ derive (Deserialize) ]
 pub struct ConfigMisc {
 	#[ serde (rename = "tab-size") ]
 	pub tab_size: usize,
+	#[ serde (rename = "escape-delay", default = "ConfigMisc::default_escape_delay") ]
+	pub escape_delay: u64,
+	#[ serde (rename = "colour-depth", default) ]
+	pub colour_depth: Option <ColourDepth>,
+	#[ serde (rename = "autosave-on-focus-out", default) ]
+	pub autosave_on_focus_out: bool,
+	#[ serde (rename = "osc52-clipboard", default) ]
+	pub osc52_clipboard: bool,
+	#[ serde (rename = "osc52-max-size", default = "ConfigMisc::default_osc52_max_size") ]
+	pub osc52_max_size: usize,
+	#[ serde (rename = "set-title", default = "ConfigMisc::default_set_title") ]
+	pub set_title: bool,
+	#[ serde (rename = "cursor-insert", default = "ConfigMisc::default_cursor_insert") ]
+	pub cursor_insert: CursorShape,
+	#[ serde (rename = "cursor-overwrite", default = "ConfigMisc::default_cursor_overwrite") ]
+	pub cursor_overwrite: CursorShape,
+	#[ serde (rename = "large-file-size", default = "ConfigMisc::default_large_file_size") ]
+	pub large_file_size: u64,
+}
+
+impl ConfigMisc {
+	fn default_escape_delay () -> u64 { 50 }
+	fn default_osc52_max_size () -> usize { 100_000 }
+	fn default_set_title () -> bool { true }
+	fn default_cursor_insert () -> CursorShape { CursorShape::Bar }
+	fn default_cursor_overwrite () -> CursorShape { CursorShape::Underline }
+	fn default_large_file_size () -> u64 { 64 << 20 }
+}
+
+#[ derive (Clone, Copy, Debug, Deserialize, Eq, PartialEq) ]
+pub enum ColourDepth {
+	#[ serde (rename = "true-colour") ]
+	TrueColour,
+	#[ serde (rename = "256") ]
+	Ansi256,
+	#[ serde (rename = "16") ]
+	Ansi16,
+}
+
+impl ColourDepth {
+
+	/// Guess the colour depth from `COLORTERM` and `TERM`
+	pub fn detect () -> Self {
+		let colorterm = env::var ("COLORTERM").unwrap_or_default ();
+		let term = env::var ("TERM").unwrap_or_default ();
+		if colorterm == "truecolor" || colorterm == "24bit" || term.contains ("direct") {
+			Self::TrueColour
+		} else if term.contains ("256color")
+				|| [ "xterm", "screen", "tmux", "rxvt" ].iter ()
+					.any (|prefix| term.starts_with (prefix)) {
+			Self::Ansi256
+		} else {
+			Self::Ansi16
+		}
+	}
+
+}
+
+#[ derive (Clone, Copy, Debug, Deserialize, Eq, PartialEq) ]
+pub enum CursorShape {
+	#[ serde (rename = "default") ]
+	Default,
+	#[ serde (rename = "block") ]
+	Block,
+	#[ serde (rename = "blinking-block") ]
+	BlinkingBlock,
+	#[ serde (rename = "underline") ]
+	Underline,
+	#[ serde (rename = "blinking-underline") ]
+	BlinkingUnderline,
+	#[ serde (rename = "bar") ]
+	Bar,
+	#[ serde (rename = "blinking-bar") ]
+	BlinkingBar,
+}
+
+impl CursorShape {
+
+	/// Parameter for the DECSCUSR sequence which selects this shape
+	pub fn decscusr (self) -> u8 {
+		match self {
+			Self::Default => 0,
+			Self::BlinkingBlock => 1,
+			Self::Block => 2,
+			Self::BlinkingUnderline => 3,
+			Self::Underline => 4,
+			Self::BlinkingBar => 5,
+			Self::Bar => 6,
+		}
+	}
+
 }
 
+#[ derive (Deserialize) ]
 pub struct FileType {
-	pub name: Rc <str>,
+	#[ serde (default) ]
+	pub extensions: Vec <Rc <str>>,
+	#[ serde (rename = "ensure-final-newline", default) ]
+	pub ensure_final_newline: bool,
+
+	/// Characters which are part of words along with letters and digits
+	#[ serde (rename = "word-chars", default = "FileType::default_word_chars") ]
+	pub word_chars: Rc <str>,
+}
+
+impl FileType {
+	pub fn default_word_chars () -> Rc <str> { "_".into () }
 }
 
 #[ derive (Deserialize) ]
@@ -54,13 +179,70 @@ pub struct ConfigTextAttr {
 	pub bold: bool,
 }
 
-#[ derive (Clone, Copy) ]
+#[ derive (Clone, Copy, Debug, Eq, Hash, PartialEq) ]
 pub struct Colour {
 	pub red: u8,
 	pub green: u8,
 	pub blue: u8,
 }
 
+impl Colour {
+
+	pub const fn new (red: u8, green: u8, blue: u8) -> Self {
+		Self { red, green, blue }
+	}
+
+	fn distance (self, other: Self) -> u32 {
+		let diff = |left: u8, right: u8| (left as i32 - right as i32).pow (2) as u32;
+		2 * diff (self.red, other.red)
+			+ 4 * diff (self.green, other.green)
+			+ 3 * diff (self.blue, other.blue)
+	}
+
+	/// Nearest entry in the xterm 256 colour palette, ignoring the configurable first sixteen
+	pub fn to_ansi_256 (self) -> u8 {
+		const LEVELS: [u8; 6] = [ 0, 95, 135, 175, 215, 255 ];
+		let nearest_level = |val: u8| (0 .. 6)
+			.min_by_key (|& idx| (LEVELS [idx] as i32 - val as i32).abs ())
+			.unwrap ();
+		let (red, green, blue) =
+			(nearest_level (self.red), nearest_level (self.green), nearest_level (self.blue));
+		let cube_idx = 16 + red * 36 + green * 6 + blue;
+		let cube = Self::new (LEVELS [red], LEVELS [green], LEVELS [blue]);
+		let grey_idx = (0 .. 24)
+			.min_by_key (|& idx| {
+				let level = 8 + idx * 10;
+				self.distance (Self::new (level, level, level))
+			})
+			.unwrap ();
+		let grey_level = 8 + grey_idx * 10;
+		let grey = Self::new (grey_level, grey_level, grey_level);
+		if self.distance (grey) < self.distance (cube) {
+			232 + grey_idx
+		} else {
+			cube_idx as u8
+		}
+	}
+
+	/// Nearest entry in the standard sixteen colour palette, using the xterm default values
+	pub fn to_ansi_16 (self) -> u8 {
+		const PALETTE: [Colour; 16] = [
+			Colour::new (0, 0, 0), Colour::new (205, 0, 0),
+			Colour::new (0, 205, 0), Colour::new (205, 205, 0),
+			Colour::new (0, 0, 238), Colour::new (205, 0, 205),
+			Colour::new (0, 205, 205), Colour::new (229, 229, 229),
+			Colour::new (127, 127, 127), Colour::new (255, 0, 0),
+			Colour::new (0, 255, 0), Colour::new (255, 255, 0),
+			Colour::new (92, 92, 255), Colour::new (255, 0, 255),
+			Colour::new (0, 255, 255), Colour::new (255, 255, 255),
+		];
+		(0 .. 16)
+			.min_by_key (|& idx| self.distance (PALETTE [idx as usize]))
+			.unwrap ()
+	}
+
+}
+
 impl <'de> Deserialize <'de> for Colour {
 	fn deserialize <De: de::Deserializer <'de>> (de: De) -> Result <Self, De::Error> {
 		struct Visitor;
@@ -71,7 +253,7 @@ impl <'de> Deserialize <'de> for Colour {
 			}
 			fn visit_str <Er: de::Error> (self, src: & str) -> Result <Colour, Er> {
 				if src.chars ().count () != 7
-						|| src.chars ().next ().unwrap () != '#'
+						|| ! src.starts_with ('#')
 						|| ! src.chars ().skip (1).all (|ch| ch.is_ascii_hexdigit ()) {
 					return Err (de::Error::invalid_value (
 						de::Unexpected::Str (src),
@@ -87,3 +269,31 @@ impl <'de> Deserialize <'de> for Colour {
 		de.deserialize_str (Visitor)
 	}
 }
+
+#[ cfg (test) ]
+mod tests {
+
+	use super::*;
+
+	#[ test ]
+	fn colour_quantisation () {
+		let cases: & [(& str, Colour, u8, u8)] = & [
+			("black", Colour::new (0, 0, 0), 16, 0),
+			("white", Colour::new (255, 255, 255), 231, 15),
+			("red", Colour::new (255, 0, 0), 196, 9),
+			("dark red", Colour::new (205, 0, 0), 160, 1),
+			("blue", Colour::new (0, 0, 238), 21, 4),
+			("light blue", Colour::new (100, 100, 255), 63, 12),
+			("cube entry", Colour::new (95, 135, 175), 67, 8),
+			("teal", Colour::new (0, 95, 95), 23, 8),
+			("darkest grey", Colour::new (8, 8, 8), 232, 0),
+			("dark grey", Colour::new (60, 60, 60), 237, 0),
+			("mid grey", Colour::new (128, 128, 128), 244, 8),
+			("near grey", Colour::new (95, 95, 100), 241, 8),
+		];
+		for & (name, colour, ansi_256, ansi_16) in cases {
+			assert_eq! ((colour.to_ansi_256 (), colour.to_ansi_16 ()), (ansi_256, ansi_16), "{name}");
+		}
+	}
+
+}
@@ -1,13 +1,16 @@
-use std::collections::HashMap;
-use std::io::Write as _;
+use std::mem;
 
+use unicode_segmentation::UnicodeSegmentation as _;
+
+use crate::buffer::*;
 use crate::config::*;
+use crate::encoding::*;
 use crate::file::*;
 use crate::misc::*;
-use crate::terminal::{ TextAttr, InputEvent as InEv, Key, Terminal };
+use crate::terminal::{ Backend, TextAttr, InputEvent as InEv, Key, MouseButton };
 
-pub struct Editor {
-	term: Terminal,
+pub struct Editor <Term: Backend> {
+	term: Term,
 	term_rows: usize,
 	term_cols: usize,
 	kill_buf: String,
@@ -17,19 +20,57 @@ pub struct Editor {
 	config: Config,
 	ui_attrs: UiAttrs,
 	error: Option <String>,
+	prompt: Option <Prompt>,
+}
+
+/// Check if an event kills text, so consecutive kills can be collected in the kill buffer
+fn is_kill (ev: & InEv) -> bool {
+	matches! (ev,
+		InEv::CtrlKey (Key::Char ('k'))
+		| InEv::AltKey (Key::Char ('d'))
+		| InEv::AltKey (Key::Backspace)
+		| InEv::CtrlKey (Key::Char ('w')))
 }
 
-impl Editor {
+/// Check if an event moves the cursor, returning whether it extends the selection
+fn movement (ev: & InEv) -> Option <bool> {
+	match ev {
+		InEv::Key (Key::Up | Key::Down | Key::Left | Key::Right | Key::PageUp | Key::PageDown | Key::Home | Key::End)
+		| InEv::CtrlKey (Key::Char ('p' | 'n' | 'b' | 'f' | 'v' | 'a' | 'e') | Key::Left | Key::Right)
+		| InEv::AltKey (Key::Char ('v' | 'b' | 'f')) => Some (false),
+		InEv::ShiftKey (Key::Up | Key::Down | Key::Left | Key::Right | Key::PageUp | Key::PageDown | Key::Home | Key::End)
+		| InEv::CtrlShiftKey (Key::Left | Key::Right) => Some (true),
+		_ => None,
+	}
+}
+
+/// Question asked on the status line, answered with Enter or abandoned with Escape
+struct Prompt {
+	label: & 'static str,
+	answer: String,
+	command: Command,
+}
+
+#[ derive (Clone, Copy, Debug) ]
+enum Command {
+	SetEncoding,
+	SetLineEnding,
+	GotoOffset,
+}
 
-	pub fn new (files: Vec <File>) -> GenResult <Self> {
-		let mut term = Terminal::new () ?;
+impl <Term: Backend> Editor <Term> {
+
+	pub fn new (mut term: Term, config: Config, files: Vec <File>) -> GenResult <Self> {
 		term.start () ?;
-		let config = Config::load () ?;
 		let ui_attrs = UiAttrs::build (& config) ?;
+		let (term_rows, term_cols) = term.size ().unwrap_or ((25, 80));
+		for file in & files {
+			file.set_tab_size (config.misc.tab_size);
+		}
 		Ok (Self {
 			term,
-			term_rows: 25,
-			term_cols: 80,
+			term_rows,
+			term_cols,
 			kill_buf: String::new (),
 			prev_event: None,
 			files,
@@ -37,61 +78,187 @@ impl Editor {
 			config,
 			ui_attrs,
 			error: None,
+			prompt: None,
 		})
 	}
 
 	pub fn run (& mut self) -> GenResult <()> {
-		write! (self.term, "\x1b[18t") ?;
-		self.term.flush () ?;
+		if self.term.size ().is_none () {
+			self.term.request_size () ?;
+		}
+		self.draw () ?;
 		loop {
 			let ev = match self.term.input () {
-				Ok (ev) => ev,
+				Ok (Some (ev)) => ev,
+				Ok (None) => break,
 				Err (err) => {
 					self.error = Some (err.to_string ());
 					self.draw () ?;
 					continue;
 				},
 			};
+			if ev == InEv::Wake {
+				self.draw () ?;
+				continue;
+			}
+			self.file ().poll_loading ();
 			let mut new_error = None;
+			if let (None, Some (shift)) = (& self.prompt, movement (& ev)) {
+				self.file ().shift_select (shift);
+			}
 			match ev {
+				InEv::Terminate { signal } =>
+					return Err (format! ("Terminated by signal {signal}").into ()),
 				InEv::TextSize { rows, cols } => {
 					self.term_rows = rows as usize;
 					self.term_cols = cols as usize;
+					self.term.resize (self.term_rows, self.term_cols);
 				},
+				ref ev @ (InEv::Key (_) | InEv::CtrlKey (_) | InEv::AltKey (_) | InEv::Paste (_))
+						if self.prompt.is_some () =>
+					new_error = self.prompt_input (ev),
+				_ if self.prompt.is_some () => (),
 				InEv::Key (Key::Char (ch)) => self.file ().type_char (ch),
-				InEv::Key (Key::Up) | InEv::CtrlKey (Key::Char ('p')) => self.file ().up (1),
-				InEv::Key (Key::Down) | InEv::CtrlKey (Key::Char ('n')) => self.file ().down (1),
-				InEv::Key (Key::Left) | InEv::CtrlKey (Key::Char ('b')) => self.file ().left (1),
-				InEv::Key (Key::Right) | InEv::CtrlKey (Key::Char ('f')) => self.file ().right (1),
-				InEv::Key (Key::PageUp) | InEv::AltKey (Key::Char ('v')) => self.file ().up (self.term_rows - 4),
-				InEv::Key (Key::PageDown) | InEv::CtrlKey (Key::Char ('v')) => self.file ().down (self.term_rows - 4),
+				InEv::Key (Key::Up) | InEv::ShiftKey (Key::Up) | InEv::CtrlKey (Key::Char ('p')) => self.file ().up (1),
+				InEv::Key (Key::Down) | InEv::ShiftKey (Key::Down) | InEv::CtrlKey (Key::Char ('n')) => self.file ().down (1),
+				InEv::Key (Key::Left) | InEv::ShiftKey (Key::Left) | InEv::CtrlKey (Key::Char ('b')) => self.file ().left (1),
+				InEv::Key (Key::Right) | InEv::ShiftKey (Key::Right) | InEv::CtrlKey (Key::Char ('f')) => self.file ().right (1),
+				InEv::Key (Key::PageUp) | InEv::ShiftKey (Key::PageUp) | InEv::AltKey (Key::Char ('v')) => self.file ().up (self.text_rows ().max (1)),
+				InEv::Key (Key::PageDown) | InEv::ShiftKey (Key::PageDown) | InEv::CtrlKey (Key::Char ('v')) => self.file ().down (self.text_rows ().max (1)),
+				InEv::Key (Key::Escape) => self.file ().cancel (),
+				InEv::Key (Key::Insert) => self.file ().toggle_overwrite (),
 				InEv::Key (Key::Backspace) => self.file ().backspace (),
 				InEv::Key (Key::Delete) | InEv::CtrlKey (Key::Char ('d')) => self.file ().delete (),
-				InEv::Key (Key::Home) | InEv::CtrlKey (Key::Char ('a')) => self.file ().home (),
-				InEv::Key (Key::End) | InEv::CtrlKey (Key::Char ('e')) => self.file ().end (),
-				InEv::CtrlKey (Key::Char ('i')) => self.file ().type_char ('\t'),
-				InEv::CtrlKey (Key::Char ('k')) => {
-					if self.prev_event != Some (InEv::CtrlKey (Key::Char ('k'))) {
-						self.kill_buf = String::new ();
+				InEv::Key (Key::Home) | InEv::ShiftKey (Key::Home) | InEv::CtrlKey (Key::Char ('a')) => self.file ().home (),
+				InEv::Key (Key::End) | InEv::ShiftKey (Key::End) | InEv::CtrlKey (Key::Char ('e')) => self.file ().end (),
+				InEv::Key (Key::Tab) => self.file ().type_char ('\t'),
+				InEv::Key (Key::Enter) => self.file ().type_char ('\n'),
+				InEv::AltKey (Key::Char ('b')) | InEv::CtrlKey (Key::Left) | InEv::CtrlShiftKey (Key::Left) => self.file ().word_left (),
+				InEv::AltKey (Key::Char ('f')) | InEv::CtrlKey (Key::Right) | InEv::CtrlShiftKey (Key::Right) => self.file ().word_right (),
+				ref ev if is_kill (ev) => {
+					// consecutive kills add to the kill buffer, but one which kills nothing leaves it alone
+					let appending = self.prev_event.as_ref ().is_some_and (is_kill);
+					let mut kill_buf = if appending { mem::take (& mut self.kill_buf) } else { String::new () };
+					let old_len = kill_buf.len ();
+					let file = & self.files [self.file_idx];
+					match * ev {
+						InEv::AltKey (Key::Char ('d')) => file.kill_word_right (& mut kill_buf),
+						InEv::AltKey (Key::Backspace) => file.kill_word_left (& mut kill_buf),
+						InEv::CtrlKey (Key::Char ('w')) => if let Err (err) = file.kill_region (& mut kill_buf) {
+							new_error = Some (err.to_string ());
+						},
+						_ => file.kill (& mut kill_buf),
+					}
+					if kill_buf.len () != old_len {
+						self.kill_buf = kill_buf;
+						if self.config.misc.osc52_clipboard {
+							if let Err (err) = self.term.set_clipboard (& self.kill_buf) {
+								new_error = Some (err.to_string ());
+							}
+						}
+					} else if appending {
+						self.kill_buf = kill_buf;
 					}
-					self.files [self.file_idx].kill (& mut self.kill_buf);
 				},
 				InEv::CtrlKey (Key::Char ('l')) => {
-					write! (self.term, "\x1b[?1049h") ?;
-					write! (self.term, "\x1b[18t") ?;
+					match self.term.size () {
+						Some ((rows, cols)) => (self.term_rows, self.term_cols) = (rows, cols),
+						None => self.term.request_size () ?,
+					}
+					self.term.resize (self.term_rows, self.term_cols);
+				},
+				InEv::CtrlKey (Key::Char ('s')) => if let Err (err) = self.file ().save () {
+					new_error = Some (err.to_string ());
 				},
-				InEv::CtrlKey (Key::Char ('m')) => self.file ().type_char ('\n'),
-				InEv::CtrlKey (Key::Char ('s')) => self.file ().save () ?,
 				InEv::CtrlKey (Key::Char ('u')) => self.file ().unkill (& self.kill_buf),
+				InEv::CtrlKey (Key::Char (' ')) => self.file ().set_mark (),
+				InEv::AltKey (Key::Char ('w')) => match self.file ().copy_region () {
+					Ok (text) => {
+						self.kill_buf = text;
+						if self.config.misc.osc52_clipboard {
+							if let Err (err) = self.term.set_clipboard (& self.kill_buf) {
+								new_error = Some (err.to_string ());
+							}
+						}
+					},
+					Err (err) => new_error = Some (err.to_string ()),
+				},
 				InEv::CtrlKey (Key::Char ('z')) => unsafe {
 					let pid = libc::getpid ();
 					self.term.stop () ?;
 					libc::kill (pid, libc::SIGSTOP);
 					self.term.start () ?;
+					if let Some ((rows, cols)) = self.term.size () {
+						(self.term_rows, self.term_cols) = (rows, cols);
+					}
+					self.term.resize (self.term_rows, self.term_cols);
+				},
+				InEv::Paste (ref text) => self.file ().paste (text),
+				InEv::Clipboard (ref text) => {
+					self.file ().paste (text);
+					self.kill_buf = LineEnding::normalise (text).into_owned ();
+				},
+				InEv::FocusIn => {
+					let changed: Vec <_> = self.files.iter ()
+						.filter (|file| file.changed_on_disk ())
+						.map (|file| file.name ())
+						.collect ();
+					if ! changed.is_empty () {
+						new_error = Some (format! ("Changed on disk: {}", changed.join (", ")));
+					}
+				},
+				InEv::FocusOut => if self.config.misc.autosave_on_focus_out {
+					for file in self.files.iter ().filter (|file| file.dirty () && file.has_filename ()) {
+						if let Err (err) = file.save () {
+							new_error = Some (format! ("Autosave {}: {err}", file.name ()));
+						}
+					}
 				},
+				InEv::MousePress { button: MouseButton::Left, row, col } => {
+					if let Some (row) = self.text_row (row) {
+						self.file ().mouse_press (row, col as usize);
+					}
+				},
+				InEv::MouseDrag { button: MouseButton::Left, row, col } => {
+					let rows = self.text_rows ();
+					let row = row as usize;
+					if row < 2 {
+						self.file ().scroll (-1, rows);
+					} else if 2 + rows <= row {
+						self.file ().scroll (1, rows);
+					}
+					let row = row.saturating_sub (2).min (rows.saturating_sub (1));
+					self.file ().mouse_drag (row, col as usize);
+				},
+				InEv::MouseRelease { button: MouseButton::Left, .. } => self.file ().mouse_release (),
+				InEv::MouseWheel { delta, row, .. } => {
+					if self.text_row (row).is_some () {
+						self.file ().scroll (delta as isize * 3, self.text_rows ());
+					}
+				},
+				InEv::AltKey (Key::Char ('c')) => self.prompt = Some (Prompt {
+					label: "Encoding",
+					answer: self.file ().encoding ().to_string (),
+					command: Command::SetEncoding,
+				}),
 				InEv::AltKey (Key::Char ('e')) => self.file ().redo (),
+				InEv::AltKey (Key::Char ('g')) => self.prompt = Some (Prompt {
+					label: "Go to offset",
+					answer: self.file ().char_offset ().map_or_else (|| "0x".to_owned (), |offset| offset.to_string ()),
+					command: Command::GotoOffset,
+				}),
+				InEv::AltKey (Key::Char ('l')) => self.prompt = Some (Prompt {
+					label: "Line ending",
+					answer: self.file ().line_ending ().to_string (),
+					command: Command::SetLineEnding,
+				}),
 				InEv::AltKey (Key::Char ('u')) => self.file ().undo (),
 				InEv::AltKey (Key::Char ('x')) => break,
+				InEv::AltKey (Key::Char ('y')) => if self.config.misc.osc52_clipboard {
+					self.term.request_clipboard () ?;
+				} else {
+					new_error = Some ("Clipboard is disabled".to_owned ());
+				},
 				InEv::AltKey (Key::Left) => {
 					self.file_idx =
 						if self.file_idx == 0 { self.files.len () - 1 }
@@ -101,7 +268,7 @@ impl Editor {
 					self.file_idx += 1;
 					if self.file_idx == self.files.len () { self.file_idx = 0; }
 				},
-				ev => new_error = Some (format! ("EVENT: {ev:?}").into ()),
+				ref ev => new_error = Some (format! ("EVENT: {ev:?}")),
 			}
 			self.error = new_error;
 			self.prev_event = Some (ev);
@@ -110,11 +277,76 @@ impl Editor {
 		Ok (())
 	}
 
-	fn file (& self) -> & File {
+	/// Edit the answer to the current prompt, and run its command when it is entered
+	fn prompt_input (& mut self, ev: & InEv) -> Option <String> {
+		let prompt = self.prompt.as_mut () ?;
+		match * ev {
+			InEv::Key (Key::Char (ch)) => prompt.answer.push (ch),
+			InEv::Paste (ref text) =>
+				prompt.answer.extend (text.chars ().filter (|& ch| ! ch.is_control ())),
+			InEv::Key (Key::Backspace) => { prompt.answer.pop (); },
+			InEv::Key (Key::Escape) | InEv::CtrlKey (Key::Char ('g')) => self.prompt = None,
+			InEv::Key (Key::Enter) => {
+				let prompt = self.prompt.take () ?;
+				return self.run_command (prompt.command, & prompt.answer).err ();
+			},
+			_ => (),
+		}
+		None
+	}
+
+	fn run_command (& mut self, command: Command, answer: & str) -> Result <(), String> {
+		match command {
+			Command::SetEncoding => {
+				let encoding = Encoding::parse (answer)
+					.ok_or_else (|| format! ("Unknown encoding: {answer}")) ?;
+				self.file ().set_encoding (encoding);
+			},
+			Command::SetLineEnding => {
+				let line_ending = LineEnding::parse (answer)
+					.ok_or_else (|| format! ("Unknown line ending: {answer}")) ?;
+				self.file ().set_line_ending (line_ending);
+			},
+			Command::GotoOffset => {
+				let answer = answer.trim ();
+				let offset = match answer.strip_prefix ("0x").or_else (|| answer.strip_prefix ("0X")) {
+					Some (digits) => usize::from_str_radix (digits, 16),
+					None => answer.parse (),
+				}.map_err (|_| format! ("Invalid offset: {answer}")) ?;
+				self.file ().goto_offset (offset);
+			},
+		}
+		Ok (())
+	}
+
+	pub fn file (& self) -> & File {
 		& self.files [self.file_idx]
 	}
 
+	fn text_rows (& self) -> usize {
+		self.term_rows.saturating_sub (4)
+	}
+
+	fn text_row (& self, row: u32) -> Option <usize> {
+		let row = row as usize;
+		(2 .. 2 + self.text_rows ()).contains (& row).then (|| row - 2)
+	}
+
 	fn draw (& mut self) -> GenResult <()> {
+		self.file ().poll_loading ();
+		if self.config.misc.set_title {
+			self.term.set_title (& format! ("{name}{dirty} [{file_idx}/{file_count}] - jtx",
+				name = self.files [self.file_idx].name (),
+				dirty = if self.files [self.file_idx].dirty () { " *" } else { "" },
+				file_idx = self.file_idx + 1,
+				file_count = self.files.len ())) ?;
+		}
+		self.term.set_cursor_shape (if self.file ().overwrite () {
+			self.config.misc.cursor_overwrite
+		} else {
+			self.config.misc.cursor_insert
+		}) ?;
+		if self.term_rows == 0 { return Ok (()) }
 		self.term.move_to (0, 0) ?;
 		self.term.text_attr (self.ui_attrs.header) ?;
 		write! (self.term, "  [{file_idx}/{file_count}]  {name}{dirty}",
@@ -123,20 +355,42 @@ impl Editor {
 			name = self.files [self.file_idx].name (),
 			dirty = if self.files [self.file_idx].dirty () { " *" } else { "" }) ?;
 		self.term.clear_to_end () ?;
+		if self.term_rows < 2 {
+			self.term.flush () ?;
+			return Ok (());
+		}
 		self.term.move_to (self.term_rows - 1, 0) ?;
 		self.term.text_attr (self.ui_attrs.status) ?;
-		if let Some (error) = self.error.as_ref () {
+		if let Some (prompt) = self.prompt.as_ref () {
+			write! (self.term, "  {label}: {answer}", label = prompt.label, answer = prompt.answer) ?;
+		} else if let Some (error) = self.error.as_ref () {
 			write! (self.term, "  ERROR: {error}") ?;
 		} else {
 			write! (self.term, "  {status}",
 				status = self.file ().status ()) ?;
 		}
 		self.term.clear_to_end () ?;
+		for row in [ 1, self.term_rows - 2 ] {
+			if row == 0 || self.term_rows - 1 <= row { continue }
+			self.term.move_to (row, 0) ?;
+			self.term.text_attr (self.ui_attrs.default) ?;
+			self.term.clear_to_end () ?;
+		}
+		if self.text_rows () == 0 {
+			self.term.flush () ?;
+			return Ok (());
+		}
 		self.files [self.file_idx].draw (
 			& mut self.term,
 			& self.ui_attrs,
 			2,
 			self.term_rows - 2) ?;
+		if let Some (prompt) = self.prompt.as_ref () {
+			let col = prompt.label.len () + 4 + prompt.answer.graphemes (true)
+				.map (cluster_width)
+				.sum::<usize> ();
+			self.term.move_to (self.term_rows - 1, col.min (self.term_cols.saturating_sub (1))) ?;
+		}
 		self.term.flush () ?;
 		Ok (())
 	}
@@ -148,17 +402,666 @@ pub struct UiAttrs {
 	pub header: TextAttr,
 	pub status: TextAttr,
 	pub line_nums: TextAttr,
+	pub selection: TextAttr,
+	pub control: TextAttr,
 }
 
 impl UiAttrs {
 
 	fn build (config: & Config) -> GenResult <Self> {
+		let default = TextAttr::build (& config.palette, & config.ui.default) ?;
+		let line_nums = TextAttr::build (& config.palette, & config.ui.line_nums) ?;
 		Ok (Self {
-			default: TextAttr::build (& config.palette, & config.ui.default) ?,
+			default,
 			header: TextAttr::build (& config.palette, & config.ui.header) ?,
 			status: TextAttr::build (& config.palette, & config.ui.status) ?,
-			line_nums: TextAttr::build (& config.palette, & config.ui.line_nums) ?,
+			line_nums,
+			selection: match config.ui.selection {
+				Some (ref selection) => TextAttr::build (& config.palette, selection) ?,
+				None => TextAttr { fg: default.bg, bg: default.fg, .. default },
+			},
+			control: match config.ui.control {
+				Some (ref control) => TextAttr::build (& config.palette, control) ?,
+				None => TextAttr { fg: line_nums.fg, .. default },
+			},
 		})
 	}
 
 }
+
+#[ cfg (test) ]
+mod tests {
+
+	use std::path::PathBuf;
+	use std::rc::Rc;
+
+	use super::*;
+	use crate::headless::*;
+	use crate::terminal::*;
+
+	const CONFIG: & str = r##"
+		[misc]
+		tab-size = 4
+		[palette]
+		black = "#000000"
+		white = "#ffffff"
+		grey = "#808080"
+		blue = "#0000aa"
+		[ui]
+		default = { fg = "white", bg = "black" }
+		header = { fg = "white", bg = "blue", bold = true }
+		status = { fg = "white", bg = "blue" }
+		line-nums = { fg = "grey", bg = "black" }
+	"##;
+
+	fn run (text: & str, rows: usize, cols: usize, events: Vec <InEv>) -> Editor <Headless> {
+		run_with_config ("", text, rows, cols, events)
+	}
+
+	fn run_with_config (
+		misc: & str,
+		text: & str,
+		rows: usize,
+		cols: usize,
+		events: Vec <InEv>,
+	) -> Editor <Headless> {
+		let config: Config = toml::from_str (& CONFIG.replace ("[misc]", & format! ("[misc]\n{misc}"))).unwrap ();
+		let buffer = Buffer::from (& Rc::new (text.to_owned ()));
+		let file = File::new ("test.txt".into (), buffer, false);
+		let mut editor = Editor::new (Headless::new (rows, cols, events), config, vec! [ file ]).unwrap ();
+		editor.run ().unwrap ();
+		editor
+	}
+
+	/// Run an editor on a file which has already been loaded, with the default config
+	fn run_file (file: & File, rows: usize, cols: usize, events: impl IntoIterator <Item = InEv>) -> Editor <Headless> {
+		let config: Config = toml::from_str (CONFIG).unwrap ();
+		let mut editor = Editor::new (Headless::new (rows, cols, events), config, vec! [ file.clone () ]).unwrap ();
+		editor.run ().unwrap ();
+		editor
+	}
+
+	fn temp_path (name: & str) -> PathBuf {
+		std::env::temp_dir ().join (format! ("jtx-test-{}-{name}", std::process::id ()))
+	}
+
+	/// Load a file from some data, by writing it to a temporary file which is removed again
+	fn load_temp (name: & str, data: & [u8], large_file_size: u64) -> File {
+		let path = temp_path (name);
+		std::fs::write (& path, data).unwrap ();
+		let file = File::load (path.to_str ().unwrap ().into (), large_file_size, false).unwrap ();
+		std::fs::remove_file (& path).unwrap ();
+		file
+	}
+
+	/// Bytes the file would be saved as
+	fn saved (file: & File) -> Vec <u8> {
+		let mut data = Vec::new ();
+		file.write_data (& mut data).unwrap ();
+		data
+	}
+
+	fn row_text (screen: & Screen, row: usize) -> String {
+		screen.row (row).iter ().map (Cell::to_string).collect::<String> ().trim_end ().to_owned ()
+	}
+
+	fn chars (src: & str) -> impl Iterator <Item = InEv> + '_ {
+		src.chars ().map (|ch| InEv::Key (Key::Char (ch)))
+	}
+
+	#[ test ]
+	fn draw_layout () {
+		let editor = run ("one\ntwo\n", 8, 30, vec! []);
+		let screen = editor.term.screen ();
+		assert! (editor.term.started ());
+		assert_eq! (row_text (screen, 0), "  [1/1]  test.txt");
+		assert_eq! (row_text (screen, 1), "");
+		assert_eq! (row_text (screen, 2), "1 one");
+		assert_eq! (row_text (screen, 3), "2 two");
+		assert_eq! (row_text (screen, 4), "");
+		assert_eq! (row_text (screen, 7), "  line 1/2  col 1/4  UTF-8  LF");
+		assert_eq! (screen.row (0) [29].attr, editor.ui_attrs.header);
+		assert_eq! (screen.row (2) [0].attr, editor.ui_attrs.line_nums);
+		assert_eq! (screen.row (2) [2].attr, editor.ui_attrs.default);
+		assert_eq! (screen.row (7) [0].attr, editor.ui_attrs.status);
+		assert_eq! (screen.cursor (), (2, 2));
+	}
+
+	#[ test ]
+	fn typing_auto_indents () {
+		let events = [ InEv::Key (Key::End), InEv::Key (Key::Enter) ].into_iter ()
+			.chain (chars ("bar"))
+			.collect ();
+		let editor = run ("  foo", 8, 30, events);
+		let screen = editor.term.screen ();
+		assert_eq! (row_text (screen, 0), "  [1/1]  test.txt *");
+		assert_eq! (row_text (screen, 2), "1   foo");
+		assert_eq! (row_text (screen, 3), "2   bar");
+		assert_eq! (screen.cursor (), (3, 7));
+	}
+
+	#[ test ]
+	fn paste_is_one_undo_step () {
+		let paste = InEv::Paste ("\nbar\n\tbaz".to_owned ());
+		let editor = run ("  foo", 8, 30, vec! [ InEv::Key (Key::End), paste.clone () ]);
+		let screen = editor.term.screen ();
+		assert_eq! (row_text (screen, 2), "1   foo");
+		assert_eq! (row_text (screen, 3), "2 bar");
+		assert_eq! (row_text (screen, 4), "3     baz");
+		assert_eq! (screen.cursor (), (4, 9));
+		let undo = InEv::AltKey (Key::Char ('u'));
+		let editor = run ("  foo", 8, 30, vec! [ InEv::Key (Key::End), paste, undo ]);
+		let screen = editor.term.screen ();
+		assert_eq! (row_text (screen, 2), "1   foo");
+		assert_eq! (row_text (screen, 3), "");
+		assert_eq! (screen.cursor (), (2, 7));
+	}
+
+	#[ test ]
+	fn scrolling_ends_undo_step () {
+		let text: String = (1 ..= 20).map (|num| format! ("line {num}\n")).collect ();
+		let events = vec! [
+			InEv::Key (Key::Char ('a')),
+			InEv::MouseWheel { delta: 1, row: 3, col: 0 },
+			InEv::Key (Key::Char ('b')),
+			InEv::AltKey (Key::Char ('u')),
+		];
+		let editor = run (& text, 10, 40, events);
+		let screen = editor.term.screen ();
+		assert_eq! (row_text (screen, 2), " 4 line 4");
+		assert_eq! (row_text (screen, 9), "  line 4/20  col 2/7  UTF-8  LF");
+		assert! (saved (editor.file ()).starts_with (b"aline 1\nline 2\nline 3\nline 4\n"));
+	}
+
+	#[ test ]
+	fn mouse_drag_selects () {
+		let button = MouseButton::Left;
+		let events = vec! [
+			InEv::MousePress { button, row: 2, col: 4 },
+			InEv::MouseDrag { button, row: 2, col: 8 },
+			InEv::MouseRelease { button, row: 2, col: 8 },
+		];
+		let editor = run ("hello world", 8, 30, events);
+		let screen = editor.term.screen ();
+		let selection = editor.ui_attrs.selection;
+		let attrs: Vec <bool> = screen.row (2) [2 .. 10].iter ()
+			.map (|cell| cell.attr == selection)
+			.collect ();
+		assert_eq! (attrs, [ false, false, true, true, true, true, false, false ]);
+		assert_eq! (screen.cursor (), (2, 8));
+	}
+
+	#[ test ]
+	fn wheel_scrolls_without_moving_cursor () {
+		let text: String = (1 ..= 20).map (|num| format! ("line {num}\n")).collect ();
+		let events = vec! [
+			InEv::Key (Key::Down),
+			InEv::Key (Key::Down),
+			InEv::Key (Key::Down),
+			InEv::Key (Key::Down),
+			InEv::MouseWheel { delta: 1, row: 3, col: 0 },
+		];
+		let editor = run (& text, 10, 40, events);
+		let screen = editor.term.screen ();
+		assert_eq! (row_text (screen, 2), " 4 line 4");
+		assert_eq! (row_text (screen, 9), "  line 5/20  col 1/7  UTF-8  LF");
+		assert_eq! (screen.cursor (), (3, 3));
+		let events = vec! [ InEv::MouseWheel { delta: 1, row: 3, col: 0 } ];
+		let editor = run (& text, 10, 40, events);
+		let screen = editor.term.screen ();
+		assert_eq! (row_text (screen, 2), " 4 line 4");
+		assert_eq! (row_text (screen, 9), "  line 4/20  col 1/7  UTF-8  LF");
+	}
+
+	#[ test ]
+	fn clipboard_round_trip () {
+		let events = vec! [
+			InEv::CtrlKey (Key::Char ('k')),
+			InEv::Key (Key::End),
+			InEv::Key (Key::Enter),
+			InEv::AltKey (Key::Char ('y')),
+		];
+		let editor = run_with_config ("osc52-clipboard = true", "one\ntwo", 8, 30, events);
+		let screen = editor.term.screen ();
+		assert_eq! (editor.term.clipboard (), "one\n");
+		assert_eq! (row_text (screen, 2), "1 two");
+		assert_eq! (row_text (screen, 3), "2 one");
+		assert_eq! (row_text (screen, 4), "3");
+		let editor = run ("one", 8, 30, vec! [ InEv::AltKey (Key::Char ('y')) ]);
+		assert_eq! (row_text (editor.term.screen (), 7), "  ERROR: Clipboard is disabled");
+		let events = vec! [
+			InEv::CtrlKey (Key::Char ('k')),
+			InEv::Key (Key::Char ('x')),
+			InEv::CtrlKey (Key::Char ('w')),
+			InEv::Key (Key::Backspace),
+			InEv::CtrlKey (Key::Char ('k')),
+		];
+		let editor = run_with_config ("osc52-clipboard = true", "one", 8, 30, events);
+		assert_eq! (row_text (editor.term.screen (), 7), "  line 1/1  col 1/1  UTF-8  LF");
+		assert_eq! (editor.kill_buf, "one");
+		assert_eq! (editor.term.clipboard (), "one");
+	}
+
+	#[ test ]
+	fn overwrite_mode () {
+		let events = [ InEv::Key (Key::Right), InEv::Key (Key::Insert) ].into_iter ()
+			.chain (chars ("xyz"))
+			.collect ();
+		let editor = run ("one\ntwo", 8, 50, events);
+		let screen = editor.term.screen ();
+		assert_eq! (row_text (screen, 2), "1 oxyz");
+		assert_eq! (row_text (screen, 3), "2 two");
+		assert_eq! (row_text (screen, 7), "  line 1/2  col 5/5  UTF-8  LF  overwrite");
+		assert_eq! (editor.term.title (), "test.txt * [1/1] - jtx");
+		assert_eq! (editor.term.cursor_shape (), CursorShape::Underline);
+		let events = [ InEv::Key (Key::Right), InEv::Key (Key::Insert) ].into_iter ()
+			.chain (chars ("xyz"))
+			.chain ([ InEv::AltKey (Key::Char ('u')), InEv::Key (Key::Insert) ])
+			.collect ();
+		let editor = run ("one\ntwo", 8, 30, events);
+		let screen = editor.term.screen ();
+		assert_eq! (row_text (screen, 2), "1 one");
+		assert_eq! (screen.cursor (), (2, 5));
+		assert_eq! (editor.term.cursor_shape (), CursorShape::Bar);
+	}
+
+	#[ test ]
+	fn wide_and_combining_chars () {
+		let events = vec! [
+			InEv::Key (Key::Right),
+			InEv::Key (Key::Right),
+			InEv::Key (Key::Down),
+		];
+		let editor = run ("日本語\ne\u{301}xyz\nabcdef", 8, 30, events);
+		let screen = editor.term.screen ();
+		assert_eq! (row_text (screen, 2), "1 日本語");
+		assert_eq! (row_text (screen, 3), "2 e\u{301}xyz");
+		assert_eq! (screen.cursor (), (3, 6));
+		assert_eq! (row_text (screen, 7), "  line 2/3  col 5/5  UTF-8  LF");
+		let editor = run ("日本語\nabcdef", 8, 30, vec! [ InEv::Key (Key::Down), InEv::Key (Key::Right) ]);
+		assert_eq! (editor.term.screen ().cursor (), (3, 3));
+		let events = vec! [ InEv::Key (Key::Down), InEv::Key (Key::End), InEv::Key (Key::Up) ];
+		let editor = run ("日本語\nabcde", 8, 30, events);
+		assert_eq! (editor.term.screen ().cursor (), (2, 6));
+	}
+
+	#[ test ]
+	fn control_chars_are_visible () {
+		let events = [ InEv::Key (Key::Down) ].into_iter ()
+			.chain (std::iter::repeat_n (InEv::Key (Key::Right), 3))
+			.chain ([ InEv::Key (Key::Up) ])
+			.collect ();
+		let editor = run ("a\x1b[2Jb\x07\u{85}\u{202e}\nxxx", 8, 40, events);
+		let screen = editor.term.screen ();
+		assert_eq! (row_text (screen, 2), "1 a^[[2Jb^G<U+0085><U+202E>");
+		assert_eq! (screen.row (2) [2].attr, editor.ui_attrs.default);
+		assert_eq! (screen.row (2) [3].attr, editor.ui_attrs.control);
+		assert_eq! (screen.row (2) [5].attr, editor.ui_attrs.default);
+		assert_eq! (screen.cursor (), (2, 5));
+		assert_eq! (row_text (screen, 7), "  line 1/2  col 4/26  UTF-8  LF");
+	}
+
+	#[ test ]
+	fn prompt_ignores_other_events () {
+		let button = MouseButton::Left;
+		let events = vec! [
+			InEv::AltKey (Key::Char ('c')),
+			InEv::ShiftKey (Key::Right),
+			InEv::CtrlShiftKey (Key::Right),
+			InEv::MousePress { button, row: 3, col: 4 },
+			InEv::MouseWheel { delta: 1, row: 3, col: 4 },
+			InEv::Key (Key::Escape),
+		];
+		let editor = run ("abc\ndef", 8, 40, events);
+		let screen = editor.term.screen ();
+		assert_eq! (row_text (screen, 7), "  line 1/2  col 1/4  UTF-8  LF");
+		assert! (screen.row (2).iter ().all (|cell| cell.attr != editor.ui_attrs.selection));
+	}
+
+	#[ test ]
+	fn encodings_round_trip () {
+		let file = load_temp ("latin1.txt", b"caf\xe9\n", u64::MAX);
+		let prompt = InEv::AltKey (Key::Char ('c'));
+		let events: Vec <_> = [ prompt.clone (), InEv::Key (Key::Escape), prompt.clone () ].into_iter ()
+			.chain (std::iter::repeat_n (InEv::Key (Key::Backspace), 7))
+			.chain (chars ("utf-8x"))
+			.collect ();
+		let editor = run_file (& file, 8, 40, events);
+		let screen = editor.term.screen ();
+		assert_eq! (row_text (screen, 2), "1 café");
+		assert_eq! (row_text (screen, 7), "  Encoding: utf-8x");
+		assert_eq! (screen.cursor (), (7, 18));
+		let events = [ prompt.clone (), InEv::Key (Key::Char ('x')), InEv::Key (Key::Enter) ];
+		let editor = run_file (& file, 8, 40, events);
+		assert_eq! (row_text (editor.term.screen (), 7), "  ERROR: Unknown encoding: Latin-1x");
+		let events = [ prompt ].into_iter ()
+			.chain (std::iter::repeat_n (InEv::Key (Key::Backspace), 7))
+			.chain (chars ("utf-16le bom"))
+			.chain ([ InEv::Key (Key::Enter) ]);
+		let editor = run_file (& file, 8, 40, events);
+		assert_eq! (row_text (editor.term.screen (), 7), "  line 1/1  col 1/5  UTF-16LE BOM  LF");
+		assert_eq! (saved (& file), b"\xff\xfec\0a\0f\0\xe9\0\n\0");
+		let file = load_temp ("cp1252.txt", b"\x93quoted\x94 \x80 5\n", u64::MAX);
+		assert_eq! (file.status (), "line 1/1  col 1/34  Latin-1  LF");
+	}
+
+	#[ test ]
+	fn failed_save_leaves_file_alone () {
+		let path = temp_path ("unsaved.txt");
+		std::fs::write (& path, b"caf\xe9\n").unwrap ();
+		let file = File::load (path.to_str ().unwrap ().into (), u64::MAX, false).unwrap ();
+		let events = [ InEv::Key (Key::Char ('€')), InEv::CtrlKey (Key::Char ('s')) ];
+		let editor = run_file (& file, 8, 40, events);
+		let data = std::fs::read (& path).unwrap ();
+		std::fs::remove_file (& path).unwrap ();
+		assert_eq! (row_text (editor.term.screen (), 7), "  ERROR: Can't save '€' as Latin-1");
+		assert_eq! (data, b"caf\xe9\n");
+		assert! (file.dirty ());
+		assert! (! temp_path ("unsaved.txt.jtx-tmp").exists ());
+		let events = [ InEv::Key (Key::Backspace), InEv::CtrlKey (Key::Char ('s')) ];
+		run_file (& file, 8, 40, events);
+		let data = std::fs::read (& path).unwrap ();
+		std::fs::remove_file (& path).unwrap ();
+		assert_eq! (data, b"caf\xe9\n");
+		assert! (! file.dirty ());
+	}
+
+	#[ test ]
+	fn line_endings_are_kept () {
+		let file = load_temp ("crlf.txt", b"one\r\ntwo\nthree\r\n", u64::MAX);
+		let events = [ InEv::Key (Key::End), InEv::Key (Key::Enter), InEv::Key (Key::Char ('x')) ];
+		let editor = run_file (& file, 8, 50, events);
+		let screen = editor.term.screen ();
+		assert_eq! (row_text (screen, 2), "1 one");
+		assert_eq! (row_text (screen, 4), "3 two");
+		assert_eq! (row_text (screen, 5), "4 three");
+		assert_eq! (row_text (screen, 7), "  line 2/4  col 2/2  UTF-8  CRLF (normalised)");
+		assert_eq! (saved (& file), b"one\r\nx\r\ntwo\r\nthree\r\n");
+		let events = [ InEv::AltKey (Key::Char ('l')) ].into_iter ()
+			.chain (std::iter::repeat_n (InEv::Key (Key::Backspace), 4))
+			.chain (chars ("cr"))
+			.chain ([ InEv::Key (Key::Enter), InEv::Paste ("a\r\nb\rc".to_owned ()) ]);
+		let editor = run_file (& file, 8, 50, events);
+		assert_eq! (row_text (editor.term.screen (), 7), "  line 4/6  col 2/2  UTF-8  CR");
+		assert_eq! (saved (& file), b"one\rxa\rb\rc\rtwo\rthree\r");
+		let file = load_temp ("crlf-large.txt", b"one\r\ntwo\nthree\r\n", 0);
+		let editor = run_file (& file, 8, 50, []);
+		assert_eq! (row_text (editor.term.screen (), 3), "2 two");
+		assert_eq! (saved (& file), b"one\r\ntwo\r\nthree\r\n");
+		assert_eq! (file.status (), "line 1/3  col 1/4  UTF-8  CRLF (normalised)");
+		file.save ().unwrap ();
+		std::fs::remove_file (temp_path ("crlf-large.txt")).unwrap ();
+		assert_eq! (file.status (), "line 1/3  col 1/4  UTF-8  CRLF");
+	}
+
+	#[ test ]
+	fn final_newline_is_kept () {
+		let file_type: Config = toml::from_str (& format! (
+			"{CONFIG}\n[[file-types]]\nextensions = [ \"txt\" ]\nensure-final-newline = true\n"))
+			.unwrap ();
+		for (name, text, ensure, expect) in [
+			("newline", "one\n", false, "one\n"),
+			("none", "one", false, "one"),
+			("ensured", "one", true, "one\n"),
+			("empty", "", true, ""),
+		] {
+			let file = load_temp (& format! ("{name}.txt"), text.as_bytes (), u64::MAX);
+			if ensure { file.set_file_type (file_type.file_type (& file.name ())); }
+			assert_eq! (saved (& file), expect.as_bytes (), "{name}");
+		}
+		let file = File::load (temp_path ("new.txt").to_str ().unwrap ().into (), u64::MAX, false).unwrap ();
+		assert_eq! (saved (& file), b"");
+		let events = [ InEv::Key (Key::Char ('x')), InEv::Key (Key::Down), InEv::Key (Key::Char ('y')) ];
+		let editor = run_file (& file, 8, 40, events);
+		assert_eq! (row_text (editor.term.screen (), 2), "1 xy");
+		assert_eq! (row_text (editor.term.screen (), 3), "");
+		assert_eq! (saved (& file), b"xy\n");
+	}
+
+	#[ test ]
+	fn default_file_type () {
+		let config: Config = toml::from_str (& format! (
+			"{CONFIG}\n[[file-types]]\nextensions = [ \"rs\" ]\n\n[[file-types]]\nword-chars = \"_-\"\n"))
+			.unwrap ();
+		let word_chars = |file_type: Option <Rc <FileType>>| file_type.unwrap ().word_chars.to_string ();
+		assert_eq! (word_chars (config.file_type ("src/main.rs")), "_");
+		assert_eq! (word_chars (config.file_type ("notes.txt")), "_-");
+		assert_eq! (word_chars (config.file_type ("Makefile")), "_-");
+		assert_eq! (word_chars (config.default_file_type ()), "_-");
+	}
+
+	#[ test ]
+	fn word_movement_and_kills () {
+		let alt = |ch| InEv::AltKey (Key::Char (ch));
+		let events = vec! [ alt ('f'), alt ('f'), InEv::CtrlKey (Key::Right), alt ('b'), InEv::Key (Key::Char ('|')) ];
+		let editor = run ("foo_bar baz-qux\nnext", 8, 40, events);
+		assert_eq! (row_text (editor.term.screen (), 2), "1 foo_bar baz-|qux");
+		let events = vec! [
+			InEv::Key (Key::Down),
+			InEv::AltKey (Key::Backspace),
+			InEv::AltKey (Key::Backspace),
+			InEv::CtrlKey (Key::Char ('u')),
+		];
+		let editor = run ("foo_bar baz-qux\nnext", 8, 40, events);
+		let screen = editor.term.screen ();
+		assert_eq! (row_text (screen, 2), "1 foo_bar baz-qux");
+		assert_eq! (row_text (screen, 3), "2 next");
+		assert_eq! (editor.kill_buf, "baz-qux\n");
+		let events = vec! [ alt ('d'), alt ('d'), alt ('d'), InEv::AltKey (Key::Char ('u')) ];
+		let editor = run ("foo_bar baz-qux\nnext", 8, 40, events);
+		assert_eq! (row_text (editor.term.screen (), 2), "1 foo_bar baz-qux");
+		assert_eq! (editor.kill_buf, "foo_bar baz-qux");
+		let editor = run ("cafe\u{301} bar", 8, 40, vec! [ alt ('f'), InEv::Key (Key::Char ('|')) ]);
+		assert_eq! (row_text (editor.term.screen (), 2), "1 cafe\u{301}| bar");
+		let editor = run ("x cafe\u{301}", 8, 40, vec! [ InEv::Key (Key::End), InEv::AltKey (Key::Backspace) ]);
+		assert_eq! (row_text (editor.term.screen (), 2), "1 x");
+		let config: Config = toml::from_str (& format! (
+			"{CONFIG}\n[[file-types]]\nextensions = [ \"txt\" ]\nword-chars = \"_-\"\n"))
+			.unwrap ();
+		let file = File::new ("test.txt".into (), Buffer::from (& Rc::new ("foo baz-qux".to_owned ())), false);
+		file.set_file_type (config.file_type (& file.name ()));
+		let events = [ InEv::Key (Key::End), InEv::AltKey (Key::Backspace) ];
+		let mut editor = Editor::new (Headless::new (8, 40, events), config, vec! [ file ]).unwrap ();
+		editor.run ().unwrap ();
+		assert_eq! (row_text (editor.term.screen (), 2), "1 foo");
+	}
+
+	#[ test ]
+	fn mark_and_region () {
+		let shift = InEv::ShiftKey;
+		let events = vec! [ InEv::Key (Key::Right), shift (Key::Right), shift (Key::Right) ];
+		let editor = run ("abcdef\nghi", 8, 40, events);
+		let screen = editor.term.screen ();
+		let selection = editor.ui_attrs.selection;
+		let attrs: Vec <bool> = screen.row (2) [2 .. 8].iter ()
+			.map (|cell| cell.attr == selection)
+			.collect ();
+		assert_eq! (attrs, [ false, true, true, false, false, false ]);
+		let events = vec! [ shift (Key::End), shift (Key::Left), InEv::CtrlKey (Key::Char ('w')) ];
+		let editor = run ("abcdef\nghi", 8, 40, events);
+		assert_eq! (row_text (editor.term.screen (), 2), "1 f");
+		assert_eq! (editor.kill_buf, "abcde");
+		let events = vec! [ shift (Key::Right), InEv::Key (Key::Right), InEv::CtrlKey (Key::Char ('w')) ];
+		let editor = run ("abcdef\nghi", 8, 40, events);
+		assert_eq! (row_text (editor.term.screen (), 2), "1 abcdef");
+		assert_eq! (row_text (editor.term.screen (), 7), "  ERROR: No region");
+		let events = vec! [
+			InEv::Key (Key::Right),
+			InEv::CtrlKey (Key::Char (' ')),
+			InEv::Key (Key::Down),
+			InEv::AltKey (Key::Char ('w')),
+			InEv::Key (Key::End),
+			InEv::CtrlKey (Key::Char ('u')),
+		];
+		let editor = run ("abcdef\nghi", 8, 40, events);
+		let screen = editor.term.screen ();
+		assert_eq! (row_text (screen, 2), "1 abcdef");
+		assert_eq! (row_text (screen, 3), "2 ghibcdef");
+		assert_eq! (row_text (screen, 4), "3 g");
+		assert_eq! (editor.kill_buf, "bcdef\ng");
+	}
+
+	#[ test ]
+	fn hex_mode () {
+		let mut bytes = b"\0\x01ABC\xff".to_vec ();
+		bytes.extend (0x10 .. 0x20);
+		let file = load_temp ("hex.bin", & bytes, u64::MAX);
+		let events: Vec <_> = [ InEv::Key (Key::Insert) ].into_iter ()
+			.chain (chars ("7f"))
+			.chain ([ InEv::Key (Key::Insert) ])
+			.chain (chars ("20\tz"))
+			.chain ([ InEv::AltKey (Key::Char ('g')) ])
+			.chain (chars ("14"))
+			.chain ([ InEv::Key (Key::Enter) ])
+			.collect ();
+		let editor = run_file (& file, 8, 80, events);
+		let screen = editor.term.screen ();
+		assert_eq! (row_text (screen, 2), "00000000  7f 20 7a 01 41 42 43 ff  10 11 12 13 14 15 16 17  . z.ABC.........");
+		assert_eq! (row_text (screen, 3), format! ("{:60}........", "00000010  18 19 1a 1b 1c 1d 1e 1f"));
+		assert_eq! (row_text (screen, 7), "  offset 0x14/0x18  hex");
+		assert_eq! (screen.cursor (), (3, 64));
+		let data = saved (& file);
+		assert_eq! (data [ .. 8], * b"\x7f\x20z\x01ABC\xff");
+		assert_eq! (data [8 .. ], bytes [6 .. ]);
+		let undo = InEv::AltKey (Key::Char ('u'));
+		run_file (& file, 8, 80, [ undo.clone (), undo ]);
+		bytes [0] = 0x7f;
+		assert_eq! (saved (& file), bytes);
+		let button = MouseButton::Left;
+		let editor = run_file (& file, 3, 80, [ InEv::MouseDrag { button, row: 0, col: 12 } ]);
+		assert_eq! (row_text (editor.term.screen (), 2), "  offset 0x1/0x16  hex");
+	}
+
+	#[ test ]
+	fn goto_char_offset () {
+		let events = [ InEv::AltKey (Key::Char ('g')), InEv::Key (Key::Backspace) ].into_iter ()
+			.chain (chars ("5"))
+			.chain ([ InEv::Key (Key::Enter), InEv::Key (Key::Char ('x')) ])
+			.collect ();
+		let editor = run ("aé\nbü\nc", 8, 30, events);
+		let screen = editor.term.screen ();
+		assert_eq! (row_text (screen, 3), "2 büx");
+		assert_eq! (row_text (screen, 7), "  line 2/3  col 4/4  UTF-8  LF");
+	}
+
+	#[ test ]
+	fn large_file_is_mapped () {
+		let text: String = (0 .. 100_000).map (|idx| format! ("line {idx}\n")).collect ();
+		let file = load_temp ("large.txt", text.as_bytes (), 0);
+		let events = [ InEv::Key (Key::Down), InEv::Key (Key::Char ('x')) ];
+		let editor = run_file (& file, 8, 30, events);
+		assert_eq! (row_text (editor.term.screen (), 3).trim_start (), "2 xline 1");
+		assert_eq! (saved (& file), text.replacen ("line 1\n", "xline 1\n", 1).as_bytes ());
+	}
+
+	#[ test ]
+	fn large_files_are_detected_like_small_ones () {
+		for large_file_size in [ 0, u64::MAX ] {
+			let file = load_temp ("utf16.txt", b"\xff\xfeh\0i\0\n\0", large_file_size);
+			assert_eq! (file.status (), "line 1/1  col 1/3  UTF-16LE BOM  LF");
+			let file = load_temp ("binary.bin", b"\0\x01ABC\xff", large_file_size);
+			assert_eq! (file.status (), "offset 0x0/0x6  hex");
+			run_file (& file, 8, 80, chars ("7f"));
+			assert_eq! (saved (& file), b"\x7f\0\x01ABC\xff");
+		}
+	}
+
+	#[ test ]
+	fn invalid_data_after_bom () {
+		for large_file_size in [ 0, u64::MAX ] {
+			let file = load_temp ("odd-utf16.bin", b"\xff\xfeh\0i", large_file_size);
+			assert_eq! (file.status (), "offset 0x0/0x5  hex");
+			let file = load_temp ("surrogate.bin", b"\xff\xfe\x00\xdch\0", large_file_size);
+			assert_eq! (file.status (), "offset 0x0/0x6  hex");
+			let file = load_temp ("bad-bom.txt", b"\xef\xbb\xbfcaf\xe9\n", large_file_size);
+			assert_eq! (file.status (), "line 1/1  col 1/8  Latin-1 (BOM ignored)  LF");
+			assert_eq! (saved (& file), b"\xef\xbb\xbfcaf\xe9\n");
+		}
+	}
+
+	#[ test ]
+	fn mapped_file_changed_on_disk () {
+		let path = temp_path ("changed.txt");
+		std::fs::write (& path, "line one\nline two\nline three\n").unwrap ();
+		let file = File::load (path.to_str ().unwrap ().into (), 0, false).unwrap ();
+		run_file (& file, 8, 30, [ InEv::Key (Key::Down), InEv::Key (Key::End) ]);
+		std::fs::write (& path, b"ab\xffde").unwrap ();
+		let editor = run_file (& file, 8, 60, [ InEv::Key (Key::Up) ]);
+		std::fs::remove_file (& path).unwrap ();
+		let screen = editor.term.screen ();
+		assert_eq! (row_text (screen, 2), "1 ab\u{fffd}de");
+		assert_eq! (row_text (screen, 3), "2");
+		assert_eq! (row_text (screen, 7), "  line 1/3  col 1/6  UTF-8  LF  invalid UTF-8 replaced");
+		let path = temp_path ("changed.bin");
+		std::fs::write (& path, b"\0\x01ABC\xff").unwrap ();
+		let file = File::load (path.to_str ().unwrap ().into (), 0, false).unwrap ();
+		std::fs::write (& path, b"\0\x01").unwrap ();
+		file.poll_loading ();
+		std::fs::remove_file (& path).unwrap ();
+		assert_eq! (file.status (), "offset 0x0/0x2  hex");
+	}
+
+	#[ test ]
+	fn mapped_invalid_utf8_is_not_saved () {
+		let file = load_temp ("latin1-large.txt", b"caf\xe9\n", 0);
+		assert_eq! (file.encoding ().to_string (), "Latin-1");
+		assert_eq! (saved (& file), b"caf\xe9\n");
+		let mut text = "x\n".repeat (600_000).into_bytes ();
+		text.extend (b"caf\xe9\n");
+		let file = load_temp ("invalid-large.txt", & text, 0);
+		let editor = run_file (& file, 8, 80, [ InEv::CtrlKey (Key::Char ('s')) ]);
+		assert_eq! (row_text (editor.term.screen (), 7),
+			"  ERROR: Invalid UTF-8 was replaced when loading, so saving would change it");
+		assert! (! temp_path ("invalid-large.txt").exists ());
+	}
+
+	#[ test ]
+	fn save_keeps_symlink_and_mode () {
+		use std::os::unix::fs::PermissionsExt as _;
+		let (target, link) = (temp_path ("target.txt"), temp_path ("link.txt"));
+		std::fs::write (& target, "one\n").unwrap ();
+		std::fs::set_permissions (& target, std::fs::Permissions::from_mode (0o640)).unwrap ();
+		std::os::unix::fs::symlink (& target, & link).unwrap ();
+		let file = File::load (link.to_str ().unwrap ().into (), u64::MAX, false).unwrap ();
+		run_file (& file, 8, 40, [ InEv::Key (Key::Char ('x')), InEv::CtrlKey (Key::Char ('s')) ]);
+		let is_link = std::fs::symlink_metadata (& link).unwrap ().file_type ().is_symlink ();
+		let mode = std::fs::metadata (& target).unwrap ().permissions ().mode ();
+		let data = std::fs::read (& target).unwrap ();
+		std::fs::remove_file (& link).unwrap ();
+		std::fs::remove_file (& target).unwrap ();
+		assert! (is_link);
+		assert_eq! (mode & 0o777, 0o640);
+		assert_eq! (data, b"xone\n");
+	}
+
+	#[ test ]
+	fn save_keeps_hard_links () {
+		let (path, other) = (temp_path ("hard.txt"), temp_path ("hard-link.txt"));
+		let text: String = (0 .. 1000).map (|idx| format! ("line {idx}\n")).collect ();
+		std::fs::write (& path, & text).unwrap ();
+		std::fs::hard_link (& path, & other).unwrap ();
+		let file = File::load (path.to_str ().unwrap ().into (), 0, false).unwrap ();
+		run_file (& file, 8, 40, [ InEv::Key (Key::Char ('x')), InEv::CtrlKey (Key::Char ('s')) ]);
+		let data = std::fs::read (& other).unwrap ();
+		std::fs::remove_file (& path).unwrap ();
+		std::fs::remove_file (& other).unwrap ();
+		assert_eq! (data, format! ("x{text}").as_bytes ());
+		assert! (! file.dirty ());
+	}
+
+	#[ test ]
+	fn tiny_terminal () {
+		let events = [ InEv::Key (Key::PageDown), InEv::Key (Key::PageUp) ].into_iter ()
+			.chain (chars ("x"))
+			.collect ();
+		let editor = run ("one\ntwo", 2, 20, events);
+		let screen = editor.term.screen ();
+		assert_eq! (row_text (screen, 0), "  [1/1]  test.txt *");
+		assert_eq! (row_text (screen, 1), "  line 1/2  col 2/5");
+		let button = MouseButton::Left;
+		let events = vec! [ InEv::MouseDrag { button, row: 2, col: 4 }, InEv::MouseDrag { button, row: 0, col: 4 } ];
+		let editor = run ("one\ntwo", 3, 20, events);
+		assert_eq! (row_text (editor.term.screen (), 2), "  line 1/2  col 1/4");
+	}
+
+}
@@ -0,0 +1,162 @@
+use std::fmt;
+use std::io::{ self, Write };
+
+use crate::misc::*;
+
+/// Character encoding of a file on disk, with or without a byte order mark
+#[ derive (Clone, Copy, Debug, Eq, PartialEq) ]
+pub struct Encoding {
+	pub charset: Charset,
+	pub bom: bool,
+}
+
+#[ derive (Clone, Copy, Debug, Eq, PartialEq) ]
+pub enum Charset {
+	Utf8,
+	Latin1,
+	Utf16Le,
+	Utf16Be,
+}
+
+impl Encoding {
+
+	pub const UTF_8: Self = Self { charset: Charset::Utf8, bom: false };
+
+	/// Work out the encoding of some data and decode it, and whether a byte order mark was ignored
+	pub fn detect (data: Vec <u8>) -> (Self, String, bool) {
+		let bom = Self::sniff_bom (& data);
+		if let Some (encoding) = bom {
+			if let Ok (text) = encoding.decode (& data [encoding.bom_len () .. ]) { return (encoding, text, false) }
+		}
+		match String::from_utf8 (data) {
+			Ok (text) => (Self::UTF_8, text, false),
+			Err (err) => {
+				let text = err.as_bytes ().iter ().map (|& by| by as char).collect ();
+				(Self { charset: Charset::Latin1, bom: false }, text, bom.is_some ())
+			},
+		}
+	}
+
+	/// Look for a byte order mark at the start of some data
+	pub fn sniff_bom (data: & [u8]) -> Option <Self> {
+		[ Charset::Utf8, Charset::Utf16Le, Charset::Utf16Be ].into_iter ()
+			.find (|charset| data.starts_with (charset.bom ()))
+			.map (|charset| Self { charset, bom: true })
+	}
+
+	/// Length of the byte order mark, or zero if there isn't one
+	pub fn bom_len (& self) -> usize {
+		if self.bom { self.charset.bom ().len () } else { 0 }
+	}
+
+	/// Parse a name as shown in the status line, such as `UTF-8` or `utf-16le bom`
+	pub fn parse (name: & str) -> Option <Self> {
+		let name = name.trim ().to_ascii_lowercase ();
+		let (name, bom) = match name.strip_suffix ("bom") {
+			Some (name) => (name.trim_end_matches ([ ' ', '-' ]), true),
+			None => (name.as_str (), false),
+		};
+		let charset = match name.replace ('_', "-").as_str () {
+			"utf-8" | "utf8" => Charset::Utf8,
+			"latin-1" | "latin1" | "iso-8859-1" => Charset::Latin1,
+			"utf-16le" | "utf16le" => Charset::Utf16Le,
+			"utf-16be" | "utf16be" => Charset::Utf16Be,
+			_ => return None,
+		};
+		if bom && charset.bom ().is_empty () { return None }
+		Some (Self { charset, bom })
+	}
+
+	pub fn decode (& self, data: & [u8]) -> GenResult <String> {
+		Ok (match self.charset {
+			Charset::Utf8 => String::from_utf8 (data.to_vec ()) ?,
+			Charset::Latin1 => data.iter ().map (|& by| by as char).collect (),
+			Charset::Utf16Le | Charset::Utf16Be => {
+				if ! data.len ().is_multiple_of (2) { return Err ("Invalid UTF-16: odd number of bytes".into ()) }
+				let units: Vec <u16> = self.utf16_units (data).collect ();
+				String::from_utf16 (& units) ?
+			},
+		})
+	}
+
+	/// Decode the start of some UTF-16 data, where the last character may be cut off
+	pub fn decode_utf16_prefix (& self, data: & [u8]) -> Option <String> {
+		let mut chars = char::decode_utf16 (self.utf16_units (& data [ .. data.len () & ! 1])).peekable ();
+		let mut text = String::new ();
+		while let Some (ch) = chars.next () {
+			match ch {
+				Ok (ch) => text.push (ch),
+				Err (_) if chars.peek ().is_none () => break,
+				Err (_) => return None,
+			}
+		}
+		Some (text)
+	}
+
+	fn utf16_units <'data> (& self, data: & 'data [u8]) -> impl Iterator <Item = u16> + 'data {
+		let little_endian = self.charset == Charset::Utf16Le;
+		data.chunks_exact (2).map (move |pair| if little_endian {
+			u16::from_le_bytes ([ pair [0], pair [1] ])
+		} else {
+			u16::from_be_bytes ([ pair [0], pair [1] ])
+		})
+	}
+
+	/// Write the byte order mark, if there should be one
+	pub fn write_bom (& self, target: & mut dyn Write) -> io::Result <()> {
+		if self.bom { target.write_all (self.charset.bom ()) ?; }
+		Ok (())
+	}
+
+	/// Write some text in this encoding, failing if a character can't be represented
+	pub fn write (& self, target: & mut dyn Write, text: & str) -> GenResult <()> {
+		match self.charset {
+			Charset::Utf8 => target.write_all (text.as_bytes ()) ?,
+			Charset::Latin1 => {
+				let data = text.chars ()
+					.map (|ch| u8::try_from (ch)
+						.map_err (|_| format! ("Can't save {ch:?} as Latin-1")))
+					.collect::<Result <Vec <u8>, _>> () ?;
+				target.write_all (& data) ?;
+			},
+			Charset::Utf16Le | Charset::Utf16Be => {
+				let data: Vec <u8> = text.encode_utf16 ()
+					.flat_map (|unit| if self.charset == Charset::Utf16Le {
+						unit.to_le_bytes ()
+					} else {
+						unit.to_be_bytes ()
+					})
+					.collect ();
+				target.write_all (& data) ?;
+			},
+		}
+		Ok (())
+	}
+
+}
+
+impl Charset {
+
+	fn bom (self) -> & 'static [u8] {
+		match self {
+			Self::Utf8 => b"\xef\xbb\xbf",
+			Self::Latin1 => b"",
+			Self::Utf16Le => b"\xff\xfe",
+			Self::Utf16Be => b"\xfe\xff",
+		}
+	}
+
+}
+
+impl fmt::Display for Encoding {
+	fn fmt (& self, formatter: & mut fmt::Formatter) -> fmt::Result {
+		formatter.write_str (match self.charset {
+			Charset::Utf8 => "UTF-8",
+			Charset::Latin1 => "Latin-1",
+			Charset::Utf16Le => "UTF-16LE",
+			Charset::Utf16Be => "UTF-16BE",
+		}) ?;
+		if self.bom { formatter.write_str (" BOM") ?; }
+		Ok (())
+	}
+}
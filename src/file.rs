@@ -1,43 +1,64 @@
+use memmap2::Mmap;
 use std::cell::RefCell;
-use std::fs::File as FsFile;
-use std::io::{ self, Read as _, Write as _ };
+use std::fs::{ self, File as FsFile, OpenOptions };
+use std::io::{ self, BufWriter, Read as _ };
 use std::iter;
+use std::mem;
+use std::os::unix::fs::{ self as unix_fs, MetadataExt as _ };
+use std::path::{ Path, PathBuf };
+use std::process;
 use std::rc::Rc;
+use std::str;
+use std::sync::Arc;
+use std::sync::mpsc::{ self, TryRecvError };
+use std::thread;
+use std::time::SystemTime;
+use unicode_segmentation::UnicodeSegmentation as _;
+use unicode_width::UnicodeWidthChar as _;
 
 use crate::*;
 use crate::buffer::*;
+use crate::encoding::*;
+use crate::hex::*;
+use crate::line::*;
 use crate::misc::*;
 use crate::terminal::*;
 
 #[ derive (Clone, Debug) ]
 enum Action {
 	Insert {
-		line_idx: usize,
-		char_idx: usize,
+		pos: Position,
 		data: String,
 	},
 	Delete {
-		line_idx: usize,
-		char_idx: usize,
+		pos: Position,
 		num_bytes: usize,
 	},
+	Replace {
+		pos: Position,
+		num_bytes: usize,
+		data: String,
+	},
 }
 
 #[ derive (Clone, Copy, Debug) ]
 enum Activity {
 	None,
 	Typing,
+	Overtyping,
 	Deleting,
 	Backspacing,
 	Killing,
+	KillingBack,
 }
 
+#[ derive (Clone) ]
 pub struct File {
 	state: Rc <RefCell <FileState>>,
 }
 
 pub struct FileState {
-	filename: Rc <str>,
+	filename: Option <Rc <str>>,
 	buffer: Buffer,
 	dirty: bool,
 	col_idx: usize,
@@ -48,11 +69,66 @@ pub struct FileState {
 	redo: Vec <Action>,
 	activity: Activity,
 	line_offset: usize,
+	mark: Option <Position>,
+	mark_transient: bool,
+	disk_stat: Option <DiskStat>,
+	overwrite: bool,
+	map: Option <Arc <Mmap>>,
+	map_source: Option <(FsFile, DiskStat)>,
+	loader: Option <mpsc::Receiver <Vec <MappedLine>>>,
+	replaced_invalid: bool,
+	encoding: Encoding,
+	line_ending: LineEnding,
+	mixed_line_endings: bool,
+	bom_ignored: bool,
+	final_newline: bool,
+	file_type: Option <Rc <FileType>>,
+	hex: Option <HexBuffer>,
+}
+
+/// Start and end of a line in a memory mapped file, and whether it is valid UTF-8
+type MappedLine = (usize, usize, bool);
+
+/// Number of lines found by the background scan of a mapped file before they are handed over
+const MAPPED_BATCH_LINES: usize = 65536;
+
+/// Create a temporary file next to another one, with a name which isn't in use yet
+fn create_temp (path: & Path) -> io::Result <(FsFile, PathBuf)> {
+	let mut idx = 0;
+	loop {
+		let mut temp_path = path.as_os_str ().to_owned ();
+		temp_path.push (format! (".jtx-tmp-{pid}-{idx}", pid = process::id ()));
+		match OpenOptions::new ().write (true).create_new (true).open (& temp_path) {
+			Ok (file) => return Ok ((file, temp_path.into ())),
+			Err (err) if err.kind () == io::ErrorKind::AlreadyExists && idx < 100 => idx += 1,
+			Err (err) => return Err (err),
+		}
+	}
+}
+
+/// Modification time and size of a file on disk, to notice changes made outside the editor
+#[ derive (Clone, Copy, Debug, Eq, PartialEq) ]
+struct DiskStat {
+	modified: SystemTime,
+	size: u64,
+}
+
+impl DiskStat {
+	fn read (path: & str) -> Option <Self> {
+		Self::of (& fs::metadata (path).ok () ?)
+	}
+	fn of (metadata: & fs::Metadata) -> Option <Self> {
+		Some (Self { modified: metadata.modified ().ok () ?, size: metadata.len () })
+	}
 }
 
 impl File {
 
 	pub fn new (filename: Rc <str>, buffer: Buffer, dirty: bool) -> Self {
+		Self::build (Some (filename), buffer, dirty)
+	}
+
+	fn build (filename: Option <Rc <str>>, buffer: Buffer, dirty: bool) -> Self {
 		Self {
 			state: Rc::new (RefCell::new (FileState {
 				filename,
@@ -66,59 +142,285 @@ impl File {
 				redo: Vec::new (),
 				activity: Activity::None,
 				line_offset: 0,
+				mark: None,
+				mark_transient: false,
+				disk_stat: None,
+				overwrite: false,
+				map: None,
+				map_source: None,
+				loader: None,
+				replaced_invalid: false,
+				encoding: Encoding::UTF_8,
+				line_ending: LineEnding::Lf,
+				mixed_line_endings: false,
+				bom_ignored: false,
+				final_newline: false,
+				file_type: None,
+				hex: None,
 			})),
 		}
 	}
 
-	pub fn load (filename: Rc <str>) -> GenResult <Self> {
+	/// Load a file, mapping it into memory if it is at least `large_file_size` bytes
+	pub fn load (filename: Rc <str>, large_file_size: u64, hex: bool) -> GenResult <Self> {
 		let mut file = match FsFile::open (& * filename) {
 			Ok (file) => file,
 			Err (err) if err.kind () == io::ErrorKind::NotFound => {
-				return Ok (Self::new (filename, Buffer::default (), true));
+				let file = Self::new (filename, Buffer::default (), true);
+				let mut state = file.state.borrow_mut ();
+				state.final_newline = true;
+				if hex { state.hex = Some (HexBuffer::new (HexData::Owned (Vec::new ()))); }
+				drop (state);
+				return Ok (file);
 			},
 			Err (err) => return Err (err.into ()),
 		};
-		let mut data = String::new ();
-		file.read_to_string (& mut data) ?;
-		let data = Rc::new (data);
-		Ok (Self::new (filename, (& data).into (), false))
+		if large_file_size <= file.metadata () ?.len () {
+			return Self::load_mapped (filename, & file, hex);
+		}
+		let mut data = Vec::new ();
+		file.read_to_end (& mut data) ?;
+		Self::decode (Some (filename), data, hex)
+	}
+
+	fn decode (filename: Option <Rc <str>>, data: Vec <u8>, hex: bool) -> GenResult <Self> {
+		let file = if hex || is_binary (& data) {
+			Self::build_hex (filename.clone (), HexData::Owned (data))
+		} else {
+			let (encoding, data, bom_ignored) = Encoding::detect (data);
+			let (line_ending, mixed) = LineEnding::detect (data.as_bytes ());
+			let data = Rc::new (data);
+			let file = Self::build (filename.clone (), Buffer::split (& data), false);
+			let mut state = file.state.borrow_mut ();
+			state.encoding = encoding;
+			state.bom_ignored = bom_ignored;
+			state.line_ending = line_ending;
+			state.mixed_line_endings = mixed;
+			state.final_newline = data.ends_with (['\n', '\r']);
+			drop (state);
+			file
+		};
+		file.state.borrow_mut ().disk_stat = filename.and_then (|filename| DiskStat::read (& filename));
+		Ok (file)
+	}
+
+	/// Make a file which is shown as hex, such as a binary one
+	fn build_hex (filename: Option <Rc <str>>, data: HexData) -> Self {
+		let file = Self::build (filename, Buffer::default (), false);
+		file.state.borrow_mut ().hex = Some (HexBuffer::new (data));
+		file
+	}
+
+	/// Map a large file into memory, and find its lines in the background so it shows straight away
+	fn load_mapped (filename: Rc <str>, file: & FsFile, hex: bool) -> GenResult <Self> {
+		// the mapping is only safe to read while the file is unchanged, see `unmap_if_changed`
+		let disk_stat = DiskStat::of (& file.metadata () ?).ok_or ("Can't read modification time") ?;
+		let map_source = Some ((file.try_clone () ?, disk_stat));
+		let map = Arc::new (unsafe { Mmap::map (file) } ?);
+		if hex || is_binary (& map) {
+			let file = Self::build_hex (Some (Rc::clone (& filename)), HexData::Mapped (map));
+			let mut state = file.state.borrow_mut ();
+			state.disk_stat = DiskStat::read (& filename);
+			state.map_source = map_source;
+			drop (state);
+			return Ok (file);
+		}
+		let encoding = Encoding::sniff_bom (& map).unwrap_or (Encoding::UTF_8);
+		let (sample, valid) = sample (& map [encoding.bom_len () .. ]);
+		if encoding.charset != Charset::Utf8 || ! valid {
+			return Self::decode (Some (filename), map.to_vec (), false);
+		}
+		let (line_ending, _) = LineEnding::detect (sample);
+		let final_newline = map.len () > encoding.bom_len ()
+			&& map.last ().is_some_and (|& by| by == b'\n' || by == b'\r');
+		let (sender, receiver) = mpsc::channel ();
+		let thread_map = Arc::clone (& map);
+		thread::spawn (move || {
+			let mut batch = Vec::new ();
+			let mut start = encoding.bom_len ();
+			loop {
+				let end = LineEnding::find (& thread_map, start);
+				let line_end = end.map_or (thread_map.len (), |(end, _)| end);
+				if end.is_some () || start < line_end || ! final_newline {
+					let valid = str::from_utf8 (& thread_map [start .. line_end]).is_ok ();
+					batch.push ((start, line_end, valid));
+				}
+				if end.is_none () || batch.len () == MAPPED_BATCH_LINES {
+					if sender.send (mem::take (& mut batch)).is_err () { return }
+					wake ();
+				}
+				let (end, ending) = some_or! (end, return);
+				start = end + ending.as_str ().len ();
+			}
+		});
+		let first = receiver.recv () ?;
+		let file = Self::new (Rc::clone (& filename), Buffer::default (), false);
+		let mut state = file.state.borrow_mut ();
+		state.line_ending = line_ending;
+		state.buffer = state.mapped_lines (& map, first).into ();
+		state.map = Some (map);
+		state.map_source = map_source;
+		state.loader = Some (receiver);
+		state.encoding = encoding;
+		state.final_newline = final_newline;
+		state.disk_stat = DiskStat::read (& filename);
+		drop (state);
+		Ok (file)
+	}
+
+	/// Add any lines found by the background scan of a mapped file, and check it is unchanged
+	pub fn poll_loading (& self) {
+		self.state.borrow_mut ().poll_loading (false);
+	}
+
+	/// Read all of standard input into a buffer with no file name
+	pub fn read_stdin (hex: bool) -> GenResult <Self> {
+		let mut data = Vec::new ();
+		io::stdin ().read_to_end (& mut data) ?;
+		let file = Self::decode (None, data, hex) ?;
+		file.state.borrow_mut ().dirty = true;
+		Ok (file)
 	}
 
 	pub fn save (& self) -> GenResult <()> {
 		let mut state = self.state.borrow_mut ();
-		let mut file = FsFile::create (& * state.filename) ?;
-		let mut first = true;
-		for line in & * state.buffer {
-			if ! first {
-				write! (file, "\n") ?;
-			} else {
-				first = false;
-			}
-			write! (file, "{}", & ** line) ?;
+		let filename = state.filename.clone ().ok_or ("No file name to save to") ?;
+		state.poll_loading (true);
+		if state.replaced_invalid {
+			return Err ("Invalid UTF-8 was replaced when loading, so saving would change it".into ());
 		}
-		file.flush () ?;
+		state.write_to (& filename) ?;
+		state.disk_stat = DiskStat::read (& filename);
 		state.dirty = false;
+		state.mixed_line_endings = false;
 		state.activity = Activity::None;
 		Ok (())
 	}
 
+	/// Write a dirty buffer to a recovery file next to the original, leaving it marked dirty
+	pub fn emergency_save (& self) -> GenResult <Option <String>> {
+		let mut state = self.state.try_borrow_mut () ?;
+		if ! state.dirty { return Ok (None) }
+		state.poll_loading (true);
+		let path = format! ("{}.jtx-save", state.filename.as_deref ().unwrap_or ("stdin"));
+		state.write_to (& path) ?;
+		Ok (Some (path))
+	}
+
+	/// Write the contents of the buffer somewhere other than its file, such as standard output
+	pub fn write_data (& self, target: & mut dyn io::Write) -> GenResult <()> {
+		let mut state = self.state.borrow_mut ();
+		state.poll_loading (true);
+		state.write_data (target)
+	}
+
 	pub fn name (& self) -> Rc <str> {
-		Rc::clone (& self.state.borrow ().filename)
+		self.state.borrow ().filename.clone ().unwrap_or_else (|| "(stdin)".into ())
+	}
+
+	/// Check if the file has a name to save to, which standard input doesn't
+	pub fn has_filename (& self) -> bool {
+		self.state.borrow ().filename.is_some ()
 	}
 
 	pub fn dirty (& self) -> bool {
 		self.state.borrow ().dirty
 	}
 
+	/// Check if the file on disk has changed since it was loaded, saved or last checked
+	pub fn changed_on_disk (& self) -> bool {
+		let mut state = self.state.borrow_mut ();
+		let disk_stat = DiskStat::read (some_or! (state.filename.as_deref (), return false));
+		if disk_stat == state.disk_stat { return false }
+		state.disk_stat = disk_stat;
+		true
+	}
+
+	pub fn encoding (& self) -> Encoding {
+		self.state.borrow ().encoding
+	}
+
+	/// Change the encoding the file will be saved in
+	pub fn set_encoding (& self, encoding: Encoding) {
+		let mut state = self.state.borrow_mut ();
+		if state.encoding == encoding { return }
+		state.encoding = encoding;
+		state.bom_ignored = false;
+		state.dirty = true;
+	}
+
+	pub fn line_ending (& self) -> LineEnding {
+		self.state.borrow ().line_ending
+	}
+
+	/// Change the line ending the file will be saved with
+	pub fn set_line_ending (& self, line_ending: LineEnding) {
+		let mut state = self.state.borrow_mut ();
+		state.mixed_line_endings = false;
+		if state.line_ending == line_ending { return }
+		state.line_ending = line_ending;
+		state.dirty = true;
+	}
+
+	/// Pass an operation to the hex view if there is one, marking the file dirty if it returns true
+	fn hex (& self, op: impl FnOnce (& mut HexBuffer, bool) -> bool) -> bool {
+		let mut state = self.state.borrow_mut ();
+		let overwrite = state.overwrite;
+		let changed = some_or! (state.hex.as_mut ().map (|hex| op (hex, overwrite)), return false);
+		state.dirty |= changed;
+		true
+	}
+
+	/// Char offset of the cursor, or `None` in hex mode
+	pub fn char_offset (& self) -> Option <usize> {
+		let state = self.state.borrow ();
+		if state.hex.is_some () { return None }
+		Some (state.buffer.char_offset (state.buffer.cursor ()))
+	}
+
+	/// Move to a byte offset in hex mode, or a char offset otherwise
+	pub fn goto_offset (& self, offset: usize) {
+		if self.hex (|hex, _| { hex.move_to (offset); false }) { return }
+		let mut state = self.state.borrow_mut ();
+		state.poll_loading (true);
+		state.activity = Activity::None;
+		let pos = state.buffer.char_position (offset);
+		state.buffer.set_cursor (pos);
+		state.fix_col_idx ();
+	}
+
+	/// Apply the settings for a type of file, such as whether it must end with a newline
+	pub fn set_file_type (& self, file_type: Option <Rc <FileType>>) {
+		self.state.borrow_mut ().file_type = file_type;
+	}
+
+	pub fn set_tab_size (& self, tab_size: usize) {
+		self.state.borrow_mut ().tab_size = tab_size.max (1);
+	}
+
+	pub fn overwrite (& self) -> bool {
+		self.state.borrow ().overwrite
+	}
+
+	pub fn toggle_overwrite (& self) {
+		let mut state = self.state.borrow_mut ();
+		state.overwrite = ! state.overwrite;
+		state.activity = Activity::None;
+	}
+
 	pub fn type_char (& self, ch: char) {
+		if self.hex (|hex, overwrite| hex.type_char (ch, overwrite)) { return }
+		if ch != '\n' && self.state.borrow ().overwrite {
+			self.overtype_char (ch);
+			return;
+		}
 		let mut state = self.state.borrow_mut ();
 		if let (Activity::Typing, Some (& mut Action::Delete { ref mut num_bytes, .. })) =
 				(state.activity, state.undo.last_mut ()) {
 			* num_bytes += ch.len_utf8 ();
 		} else {
 			let action = Action::Delete {
-				line_idx: state.buffer.line_idx (),
-				char_idx: state.buffer.char_idx (),
+				pos: state.buffer.cursor (),
 				num_bytes: ch.len_utf8 (),
 			};
 			state.undo.push (action);
@@ -127,7 +429,7 @@ impl File {
 		state.buffer.insert_char (ch);
 		if ch == '\n' && state.auto_indent {
 			let indent: Vec <char> =
-				state.buffer [state.buffer.line_idx () - 1].chars ()
+				state.buffer.line_at (state.buffer.line_idx () - 1).chars ()
 					.take_while (|& ch| ch == ' ' || ch == '\t')
 					.collect ();
 			for ch in indent {
@@ -137,9 +439,37 @@ impl File {
 		state.fix_col_idx ();
 		state.activity = if ch != '\n' { Activity::Typing } else { Activity::None };
 		state.dirty = true;
+		state.mark = None;
+	}
+
+	fn overtype_char (& self, ch: char) {
+		let mut state = self.state.borrow_mut ();
+		let old_cluster = if ch.width () != Some (0) {
+			let len = state.buffer.line_right ().graphemes (true).next ().map_or (0, str::len);
+			state.buffer.cut_bytes_right (len)
+		} else { String::new () };
+		if let (Activity::Overtyping, Some (& mut Action::Replace { ref mut num_bytes, ref mut data, .. })) =
+				(state.activity, state.undo.last_mut ()) {
+			* num_bytes += ch.len_utf8 ();
+			data.push_str (& old_cluster);
+		} else {
+			let action = Action::Replace {
+				pos: state.buffer.cursor (),
+				num_bytes: ch.len_utf8 (),
+				data: old_cluster,
+			};
+			state.undo.push (action);
+			state.redo.clear ();
+		}
+		state.buffer.insert_char (ch);
+		state.fix_col_idx ();
+		state.activity = Activity::Overtyping;
+		state.dirty = true;
+		state.mark = None;
 	}
 
 	pub fn undo (& self) {
+		if self.hex (|hex, _| hex.undo ()) { return }
 		let mut state = self.state.borrow_mut ();
 		if let Some (action) = state.undo.pop () {
 			let action = state.perform (action);
@@ -149,6 +479,7 @@ impl File {
 	}
 
 	pub fn redo (& self) {
+		if self.hex (|hex, _| hex.redo ()) { return }
 		let mut state = self.state.borrow_mut ();
 		if let Some (action) = state.redo.pop () {
 			let action = state.perform (action);
@@ -158,6 +489,7 @@ impl File {
 	}
 
 	pub fn up (& self, num: usize) {
+		if self.hex (|hex, _| { hex.up (num); false }) { return }
 		let mut state = self.state.borrow_mut ();
 		state.activity = Activity::None;
 		if num < state.buffer.line_idx () {
@@ -169,6 +501,7 @@ impl File {
 	}
 
 	pub fn down (& self, num: usize) {
+		if self.hex (|hex, _| { hex.down (num); false }) { return }
 		let mut state = self.state.borrow_mut ();
 		state.activity = Activity::None;
 		if state.buffer.line_idx () + num < state.buffer.num_lines () {
@@ -181,6 +514,7 @@ impl File {
 	}
 
 	pub fn left (& self, num: usize) {
+		if self.hex (|hex, _| { hex.left (num); false }) { return }
 		let mut state = self.state.borrow_mut ();
 		state.activity = Activity::None;
 		state.buffer.move_left (num);
@@ -188,6 +522,7 @@ impl File {
 	}
 
 	pub fn right (& self, num: usize) {
+		if self.hex (|hex, _| { hex.right (num); false }) { return }
 		let mut state = self.state.borrow_mut ();
 		state.activity = Activity::None;
 		state.buffer.move_right (num);
@@ -195,6 +530,7 @@ impl File {
 	}
 
 	pub fn home (& self) {
+		if self.hex (|hex, _| { hex.home (); false }) { return }
 		let mut state = self.state.borrow_mut ();
 		state.activity = Activity::None;
 		let line_idx = state.buffer.line_idx ();
@@ -203,6 +539,7 @@ impl File {
 	}
 
 	pub fn end (& self) {
+		if self.hex (|hex, _| { hex.end (); false }) { return }
 		let mut state = self.state.borrow_mut ();
 		state.activity = Activity::None;
 		let line_idx = state.buffer.line_idx ();
@@ -212,6 +549,7 @@ impl File {
 	}
 
 	pub fn delete (& self) {
+		if self.hex (|hex, _| hex.delete ()) { return }
 		let mut state = self.state.borrow_mut ();
 		let ch = some_or! (state.buffer.delete_char_right (), return);
 		if ch != '\n' && matches! (state.activity, Activity::Deleting) {
@@ -220,8 +558,7 @@ impl File {
 			} else { unreachable! () }
 		} else {
 			let action = Action::Insert {
-				line_idx: state.buffer.line_idx (),
-				char_idx: state.buffer.char_idx (),
+				pos: state.buffer.cursor (),
 				data: ch.to_string (),
 			};
 			state.undo.push (action);
@@ -229,23 +566,24 @@ impl File {
 		state.redo.clear ();
 		state.activity = Activity::Deleting;
 		state.dirty = true;
+		state.mark = None;
 	}
 
 	pub fn backspace (& self) {
+		if self.hex (|hex, _| hex.backspace ()) { return }
 		let mut state = self.state.borrow_mut ();
 		let ch = some_or! (state.buffer.delete_char_left (), return);
 		state.fix_col_idx ();
 		if ch != '\n' && matches! (state.activity, Activity::Backspacing) {
-			let buf_char_idx = state.buffer.char_idx ();
-			if let Some (& mut Action::Insert { ref mut char_idx, ref mut data, .. }) =
+			let cursor = state.buffer.cursor ();
+			if let Some (& mut Action::Insert { ref mut pos, ref mut data }) =
 					state.undo.last_mut () {
-				* char_idx = buf_char_idx;
+				* pos = cursor;
 				data.insert (0, ch);
 			} else { unreachable! () }
 		} else {
 			let action = Action::Insert {
-				line_idx: state.buffer.line_idx (),
-				char_idx: state.buffer.char_idx (),
+				pos: state.buffer.cursor (),
 				data: ch.to_string (),
 			};
 			state.undo.push (action);
@@ -253,154 +591,500 @@ impl File {
 		state.redo.clear ();
 		state.activity = Activity::Backspacing;
 		state.dirty = true;
+		state.mark = None;
 	}
 
 	pub fn kill (& self, kill_buf: & mut String) {
+		if self.hex (|_, _| false) { return }
 		let mut state = self.state.borrow_mut ();
-		let temp = state.buffer.cut_line ();
-		kill_buf.push_str (& temp);
-		state.fix_col_idx ();
-		state.dirty = true;
-		if matches! (state.activity, Activity::Killing) {
-			if let Some (Action::Insert { ref mut data, .. }) = state.undo.last_mut () {
-				data.push_str (& temp);
-			} else { unreachable! () }
+		let line_idx = state.buffer.line_idx ();
+		let end = if line_idx + 1 < state.buffer.num_lines () {
+			Position::new (line_idx + 1, 0)
 		} else {
-			let action = Action::Insert {
-				line_idx: state.buffer.line_idx (),
-				char_idx: 0,
-				data: temp,
-			};
-			state.undo.push (action);
-		}
-		state.redo.clear ();
-		state.activity = Activity::Killing;
+			Position::new (line_idx, state.buffer.line ().len ())
+		};
+		state.kill_range (Range::new (Position::new (line_idx, 0), end), kill_buf, false);
+	}
+
+	pub fn word_left (& self) {
+		if self.hex (|_, _| false) { return }
+		let mut state = self.state.borrow_mut ();
+		let pos = state.buffer.prev_word (state.buffer.cursor (), |ch| state.is_word_char (ch));
+		state.buffer.set_cursor (pos);
+		state.fix_col_idx ();
+		state.activity = Activity::None;
+	}
+
+	pub fn word_right (& self) {
+		if self.hex (|_, _| false) { return }
+		let mut state = self.state.borrow_mut ();
+		let pos = state.buffer.next_word (state.buffer.cursor (), |ch| state.is_word_char (ch));
+		state.buffer.set_cursor (pos);
+		state.fix_col_idx ();
+		state.activity = Activity::None;
+	}
+
+	pub fn kill_word_left (& self, kill_buf: & mut String) {
+		if self.hex (|_, _| false) { return }
+		let mut state = self.state.borrow_mut ();
+		let cursor = state.buffer.cursor ();
+		let start = state.buffer.prev_word (cursor, |ch| state.is_word_char (ch));
+		state.kill_range (Range::new (start, cursor), kill_buf, true);
+	}
+
+	pub fn kill_word_right (& self, kill_buf: & mut String) {
+		if self.hex (|_, _| false) { return }
+		let mut state = self.state.borrow_mut ();
+		let cursor = state.buffer.cursor ();
+		let end = state.buffer.next_word (cursor, |ch| state.is_word_char (ch));
+		state.kill_range (Range::new (cursor, end), kill_buf, false);
 	}
 
 	pub fn unkill (& self, kill_buf: & str) {
+		if self.hex (|hex, _| hex.paste (kill_buf)) { return }
 		let mut state = self.state.borrow_mut ();
 		let action = Action::Delete {
-			line_idx: state.buffer.line_idx (),
-			char_idx: state.buffer.char_idx (),
+			pos: state.buffer.cursor (),
 			num_bytes: kill_buf.len (),
 		};
 		state.undo.push (action);
 		state.buffer.insert_str (kill_buf);
 		state.fix_col_idx ();
 		state.activity = Activity::None;
+		state.mark = None;
+	}
+
+	/// Insert text from outside the editor, such as the terminal or the system clipboard
+	pub fn paste (& self, text: & str) {
+		if self.hex (|hex, _| hex.paste (text)) { return }
+		let text = LineEnding::normalise (text);
+		let mut state = self.state.borrow_mut ();
+		let action = Action::Delete {
+			pos: state.buffer.cursor (),
+			num_bytes: text.len (),
+		};
+		state.undo.push (action);
+		state.redo.clear ();
+		state.buffer.insert_str (& text);
+		state.fix_col_idx ();
+		state.activity = Activity::None;
+		state.dirty = true;
+		state.mark = None;
+	}
+
+	pub fn cancel (& self) {
+		if self.hex (|hex, _| { hex.cancel (); false }) { return }
+		let mut state = self.state.borrow_mut ();
+		state.activity = Activity::None;
+		state.mark = None;
 	}
 
-	pub fn draw (
+	/// Set the mark at the cursor, or clear it if it is already there
+	pub fn set_mark (& self) {
+		if self.hex (|_, _| false) { return }
+		let mut state = self.state.borrow_mut ();
+		let cursor = state.buffer.cursor ();
+		state.mark = if state.mark == Some (cursor) { None } else { Some (cursor) };
+		state.mark_transient = false;
+		state.activity = Activity::None;
+	}
+
+	/// Prepare to move the cursor, selecting text if `shift` is held
+	pub fn shift_select (& self, shift: bool) {
+		if self.hex (|_, _| false) { return }
+		let mut state = self.state.borrow_mut ();
+		if shift && state.mark.is_none () {
+			state.mark = Some (state.buffer.cursor ());
+			state.mark_transient = true;
+		} else if ! shift && state.mark_transient {
+			state.mark = None;
+		}
+	}
+
+	/// Cut the text between the mark and the cursor into the kill buffer
+	pub fn kill_region (& self, kill_buf: & mut String) -> GenResult <()> {
+		let mut state = self.state.borrow_mut ();
+		let region = state.region ().ok_or ("No region") ?;
+		state.kill_range (region, kill_buf, false);
+		Ok (())
+	}
+
+	/// Text between the mark and the cursor, which also clears the mark
+	pub fn copy_region (& self) -> GenResult <String> {
+		let mut state = self.state.borrow_mut ();
+		let region = state.region ().ok_or ("No region") ?;
+		state.mark = None;
+		Ok (state.buffer.text (region))
+	}
+
+	pub fn mouse_press (& self, row: usize, col: usize) {
+		if self.hex (|hex, _| { hex.mouse_press (row, col); false }) { return }
+		let mut state = self.state.borrow_mut ();
+		state.activity = Activity::None;
+		state.move_to_view (row, col);
+		state.mark = Some (state.buffer.cursor ());
+		state.mark_transient = true;
+	}
+
+	pub fn mouse_drag (& self, row: usize, col: usize) {
+		if self.hex (|_, _| false) { return }
+		let mut state = self.state.borrow_mut ();
+		if state.mark.is_none () { return }
+		state.move_to_view (row, col);
+	}
+
+	pub fn mouse_release (& self) {
+		let mut state = self.state.borrow_mut ();
+		if state.mark == Some (state.buffer.cursor ()) {
+			state.mark = None;
+		}
+	}
+
+	pub fn scroll (& self, delta: isize, rows: usize) {
+		if self.hex (|hex, _| { hex.scroll (delta, rows); false }) { return }
+		let mut state = self.state.borrow_mut ();
+		let max_offset = state.buffer.num_lines () - 1;
+		state.line_offset = state.line_offset.saturating_add_signed (delta).min (max_offset);
+		let line_idx = state.buffer.line_idx ();
+		if line_idx < state.line_offset {
+			let line_offset = state.line_offset;
+			state.set_line_idx (line_offset);
+			state.activity = Activity::None;
+		} else if state.line_offset + rows <= line_idx {
+			let line_idx = (state.line_offset + rows).saturating_sub (1);
+			state.set_line_idx (line_idx);
+			state.activity = Activity::None;
+		}
+	}
+
+	pub fn draw <Term: Backend> (
 		& self,
-		term: & mut Terminal,
+		term: & mut Term,
 		ui_attrs: & UiAttrs,
 		start: usize,
 		end: usize,
 	) -> GenResult <()> {
 		let mut state = self.state.borrow_mut ();
-		let line_num_len = (state.buffer.num_lines () + 1).to_string ().len ();
-		let mut buf = String::new ();
+		if let Some (hex) = state.hex.as_mut () { return hex.draw (term, ui_attrs, start, end) }
+		let line_num_len = state.gutter_width () - 1;
 		if state.buffer.line_idx () < state.line_offset {
 			state.line_offset = state.buffer.line_idx ();
 		}
 		if state.line_offset + end - start - 1 < state.buffer.line_idx () {
 			state.line_offset = state.buffer.line_idx () - (end - start - 1);
 		}
+		let region = state.region ();
 		for ((row_idx, line_idx), line) in (start .. end)
 				.zip (state.line_offset .. )
-				.zip (state.buffer [state.line_offset .. ].iter ()
+				.zip (state.buffer.lines_from (state.line_offset)
 					.map (Some)
 					.chain (iter::repeat (None))) {
-			buf.clear ();
 			term.move_to (row_idx, 0) ?;
 			let mut col = 0;
 			if let Some (line) = line {
 				term.text_attr (ui_attrs.line_nums) ?;
 				write! (term, "{line_num:line_num_len$} ", line_num = line_idx + 1) ?;
 				term.text_attr (ui_attrs.default) ?;
-				for ch in line.chars () {
-					if ch == '\t' {
-						let num = state.tab_size - col % state.tab_size;
-						for _ in 0 .. num {
-							write! (term, " ") ?;
-							col += 1;
-						}
-					} else {
-						write! (term, "{ch}") ?;
-						col += 1;
+				let selected = |char_idx| region.is_some_and (|region|
+					region.contains (Position::new (line_idx, char_idx)));
+				let mut in_region = false;
+				for (char_idx, cluster) in line.grapheme_indices (true)
+						.map (|(char_idx, cluster)| (char_idx, Some (cluster)))
+						.chain (iter::once ((line.len (), None))) {
+					if in_region != selected (char_idx) {
+						in_region = ! in_region;
+						term.text_attr (if in_region { ui_attrs.selection } else { ui_attrs.default }) ?;
+					}
+					match cluster {
+						None => if in_region { write! (term, " ") ?; },
+						Some (cluster @ "\t") => {
+							let next_col = state.next_col (col, cluster);
+							write! (term, "{:1$}", "", next_col - col) ?;
+							col = next_col;
+						},
+						Some (cluster) => {
+							if let Some (name) = control_name (cluster) {
+								if ! in_region { term.text_attr (ui_attrs.control) ?; }
+								write! (term, "{name}") ?;
+								if ! in_region { term.text_attr (ui_attrs.default) ?; }
+							} else {
+								write! (term, "{cluster}") ?;
+							}
+							col = state.next_col (col, cluster);
+						},
 					}
 				}
+				if in_region {
+					term.text_attr (ui_attrs.default) ?;
+				}
 			}
 			term.clear_to_end () ?
 		}
 		term.move_to (
 			start + state.buffer.line_idx () - state.line_offset,
 			line_num_len + 1 + state.col_idx) ?;
-		term.flush () ?;
 		Ok (())
 	}
 
 	pub fn status (& self) -> String {
 		let state = self.state.borrow ();
+		if let Some (hex) = state.hex.as_ref () {
+			return format! ("{status}{overwrite}",
+				status = hex.status (),
+				overwrite = if state.overwrite { "  overwrite" } else { "" });
+		}
 		format! (
-			"line {line}/{lines}  col {col}/{cols}",
+			"line {line}/{lines}  col {col}/{cols}  {encoding}{bom_ignored}  {line_ending}{mixed}{overwrite}{loading}{invalid}",
 			line = state.buffer.line_idx () + 1,
 			lines = state.buffer.num_lines (),
 			col = state.col_idx + 1,
-			cols = 1 + state.buffer.line ().chars ()
-				.fold (0, |cols, ch| cols + if ch == '\t' {
-					state.tab_size - cols % state.tab_size
-				} else { 1 }))
+			cols = 1 + state.buffer.line ().graphemes (true)
+				.fold (0, |cols, cluster| state.next_col (cols, cluster)),
+			encoding = state.encoding,
+			bom_ignored = if state.bom_ignored { " (BOM ignored)" } else { "" },
+			line_ending = state.line_ending,
+			mixed = if state.mixed_line_endings { " (normalised)" } else { "" },
+			overwrite = if state.overwrite { "  overwrite" } else { "" },
+			loading = if state.loader.is_some () { "  loading" } else { "" },
+			invalid = if state.replaced_invalid { "  invalid UTF-8 replaced" } else { "" })
 	}
 
 }
 
 impl FileState {
 
+	/// Write to a temporary file and rename it over `path`, or write in place if that isn't possible
+	fn write_to (& mut self, path: & str) -> GenResult <()> {
+		let path = fs::canonicalize (path).unwrap_or_else (|_| path.into ());
+		let metadata = fs::metadata (& path).ok ();
+		if metadata.as_ref ().is_some_and (|metadata| 1 < metadata.nlink ()) {
+			return self.write_in_place (& path);
+		}
+		let (temp, temp_path) = match create_temp (& path) {
+			Ok (temp) => temp,
+			Err (_) => return self.write_in_place (& path),
+		};
+		let result = (|| -> GenResult <()> {
+			if let Some (metadata) = metadata {
+				// only root can give a file to someone else, so keep as much as is allowed
+				if unix_fs::fchown (& temp, Some (metadata.uid ()), Some (metadata.gid ())).is_err () {
+					unix_fs::fchown (& temp, None, Some (metadata.gid ())).ok ();
+				}
+				temp.set_permissions (metadata.permissions ()) ?;
+			}
+			self.write_data (& mut BufWriter::new (& temp)) ?;
+			fs::rename (& temp_path, & path) ?;
+			Ok (())
+		}) ();
+		if result.is_err () { let _ = fs::remove_file (& temp_path); }
+		result
+	}
+
+	/// Overwrite a file directly, for when a temporary file can't replace it
+	fn write_in_place (& mut self, path: & Path) -> GenResult <()> {
+		// the mapping would see the file being truncated while it is read
+		self.unmap_if_changed ();
+		if self.map_source.is_some () { self.unmap (usize::MAX) }
+		let file = FsFile::create (path) ?;
+		self.write_data (& mut BufWriter::new (file))
+	}
+
+	/// Stop reading a mapped file once it changes on disk, since its contents can't be trusted any more
+	fn unmap_if_changed (& mut self) {
+		let (file, disk_stat) = some_or! (self.map_source.as_ref (), return);
+		let metadata = some_or! (file.metadata ().ok (), return);
+		if DiskStat::of (& metadata).as_ref () == Some (disk_stat) { return }
+		self.unmap (usize::try_from (metadata.len ()).unwrap_or (usize::MAX));
+	}
+
+	/// Copy everything which refers to a mapped file, keeping only what is within the given size
+	fn unmap (& mut self, size: usize) {
+		self.map_source = None;
+		if let Some (hex) = self.hex.as_mut () {
+			hex.unmap (size);
+			return;
+		}
+		let truncated = self.map.as_ref ().is_some_and (|map| size < map.len ());
+		if truncated { self.loader = None } else { self.poll_loading (true) }
+		self.map = None;
+		let valid = self.buffer.unmap (size);
+		if truncated || ! valid {
+			self.replaced_invalid |= ! valid;
+			self.undo.clear ();
+			self.redo.clear ();
+			self.mark = None;
+			self.activity = Activity::None;
+			self.fix_col_idx ();
+		}
+	}
+
+	/// Add lines found by the background scan of a mapped file, waiting for all of them if `block`
+	fn poll_loading (& mut self, block: bool) {
+		self.unmap_if_changed ();
+		let loader = some_or! (self.loader.as_ref (), return);
+		let map = Arc::clone (self.map.as_ref ().unwrap ());
+		let mut lines = Vec::new ();
+		let finished = loop {
+			let result = if block {
+				loader.recv ().map_err (|_| TryRecvError::Disconnected)
+			} else {
+				loader.try_recv ()
+			};
+			match result {
+				Ok (batch) => lines.extend (batch),
+				Err (TryRecvError::Empty) => break false,
+				Err (TryRecvError::Disconnected) => break true,
+			}
+		};
+		if finished { self.loader = None }
+		let lines = self.mapped_lines (& map, lines);
+		self.buffer.append_lines (lines);
+	}
+
+	/// Make lines from those found in a mapped file, noting invalid UTF-8 and mixed line endings
+	fn mapped_lines (& mut self, map: & Arc <Mmap>, lines: Vec <MappedLine>) -> Vec <Line> {
+		lines.into_iter ()
+			.inspect (|& (_, end, _)| {
+				if LineEnding::at (map, end).is_some_and (|ending| ending != self.line_ending) {
+					self.mixed_line_endings = true;
+				}
+			})
+			.map (|(start, end, valid)| if valid {
+				Line::Mapped (Arc::clone (map), start, end)
+			} else {
+				self.replaced_invalid = true;
+				Line::Owned (String::from_utf8_lossy (& map [start .. end]).into_owned ())
+			})
+			.collect ()
+	}
+
+	fn write_data (& self, target: & mut dyn io::Write) -> GenResult <()> {
+		if let Some (hex) = self.hex.as_ref () {
+			target.write_all (hex.data ()) ?;
+			target.flush () ?;
+			return Ok (());
+		}
+		self.encoding.write_bom (target) ?;
+		let mut first = true;
+		for line in self.buffer.lines_from (0) {
+			if ! first {
+				self.encoding.write (target, self.line_ending.as_str ()) ?;
+			} else {
+				first = false;
+			}
+			self.encoding.write (target, line) ?;
+		}
+		let ensure = self.file_type.as_ref ().is_some_and (|file_type| file_type.ensure_final_newline);
+		if (self.final_newline || ensure) && 0 < self.buffer.num_bytes () {
+			self.encoding.write (target, self.line_ending.as_str ()) ?;
+		}
+		target.flush () ?;
+		Ok (())
+	}
+
+	/// Cut a range into the kill buffer, adding it to the end unless `backward` is set
+	fn kill_range (& mut self, range: Range, kill_buf: & mut String, backward: bool) {
+		let text = self.buffer.cut (range);
+		self.buffer.set_cursor (range.start);
+		self.fix_col_idx ();
+		self.mark = None;
+		if text.is_empty () { return }
+		self.redo.clear ();
+		self.dirty = true;
+		match (self.activity, backward, self.undo.last_mut ()) {
+			(Activity::Killing, false, Some (& mut Action::Insert { pos, ref mut data }))
+					if pos == range.start => {
+				data.push_str (& text);
+			},
+			(Activity::KillingBack, true, Some (& mut Action::Insert { ref mut pos, ref mut data }))
+					if * pos == range.end => {
+				* pos = range.start;
+				data.insert_str (0, & text);
+			},
+			_ => self.undo.push (Action::Insert { pos: range.start, data: text.clone () }),
+		}
+		if backward { kill_buf.insert_str (0, & text) } else { kill_buf.push_str (& text) }
+		self.activity = if backward { Activity::KillingBack } else { Activity::Killing };
+	}
+
+	/// Check if a char is part of a word, according to the file type
+	fn is_word_char (& self, ch: char) -> bool {
+		ch.is_alphanumeric () || self.file_type.as_ref ()
+			.map_or_else (FileType::default_word_chars, |file_type| Rc::clone (& file_type.word_chars))
+			.contains (ch)
+	}
+
 	fn perform (& mut self, action: Action) -> Action {
 		self.dirty = true;
+		self.mark = None;
 		match action {
-			Action::Delete { line_idx, char_idx, num_bytes } => {
-				self.buffer.move_to (line_idx, char_idx);
+			Action::Delete { pos, num_bytes } => {
+				let end = self.buffer.position (self.buffer.offset (pos) + num_bytes);
+				let data = self.buffer.cut (Range::new (pos, end));
+				self.buffer.set_cursor (pos);
 				self.fix_col_idx ();
-				let data = self.buffer.cut_bytes_right (num_bytes);
-				Action::Insert { line_idx, char_idx, data }
+				Action::Insert { pos, data }
 			},
-			Action::Insert { line_idx, char_idx, data } => {
-				self.buffer.move_to (line_idx, char_idx);
-				let action = Action::Delete { line_idx, char_idx, num_bytes: data.len () };
-				self.buffer.insert_str (& data);
+			Action::Insert { pos, data } => {
+				let end = self.buffer.replace (Range::at (pos), & data);
+				self.buffer.set_cursor (end);
 				self.fix_col_idx ();
-				action
+				Action::Delete { pos, num_bytes: data.len () }
+			},
+			Action::Replace { pos, num_bytes, data } => {
+				let range = Range::new (pos, self.buffer.position (self.buffer.offset (pos) + num_bytes));
+				let old_data = self.buffer.text (range);
+				let end = self.buffer.replace (range, & data);
+				self.buffer.set_cursor (end);
+				self.fix_col_idx ();
+				Action::Replace { pos, num_bytes: data.len (), data: old_data }
 			},
 		}
 	}
 	
+	fn gutter_width (& self) -> usize {
+		(self.buffer.num_lines () + 1).to_string ().len () + 1
+	}
+
+	fn region (& self) -> Option <Range> {
+		Some (Range::new (self.mark ?, self.buffer.cursor ()))
+	}
+
+	fn move_to_view (& mut self, row: usize, col: usize) {
+		let line_idx = (self.line_offset + row).min (self.buffer.num_lines () - 1);
+		self.saved_col_idx = col.saturating_sub (self.gutter_width ());
+		self.set_line_idx (line_idx);
+		self.fix_col_idx ();
+	}
+
+	/// Column after a grapheme cluster which starts at the given column
+	fn next_col (& self, col: usize, cluster: & str) -> usize {
+		if cluster == "\t" {
+			col - col % self.tab_size + self.tab_size
+		} else {
+			col + cluster_width (cluster)
+		}
+	}
+
 	fn set_line_idx (& mut self, line_idx: usize) {
-		let mut char_idx = 0;
-		self.col_idx = 0;
-		for ch in self.buffer [line_idx].chars () {
-			let next_col_idx = if ch == '\t' {
-				self.col_idx - self.col_idx % self.tab_size + self.tab_size
-			} else {
-				self.col_idx + 1
-			};
-			if self.saved_col_idx < next_col_idx { break }
-			self.col_idx = next_col_idx;
-			char_idx += 1;
+		let line = self.buffer.line_at (line_idx);
+		let mut char_idx = line.len ();
+		let mut col_idx = 0;
+		for (cluster_idx, cluster) in line.grapheme_indices (true) {
+			let next_col_idx = self.next_col (col_idx, cluster);
+			if self.saved_col_idx < next_col_idx {
+				char_idx = cluster_idx;
+				break;
+			}
+			col_idx = next_col_idx;
 		}
+		self.col_idx = col_idx;
 		self.buffer.move_to (line_idx, char_idx);
 	}
 
 	fn fix_col_idx (& mut self) {
-		self.col_idx = 0;
-		for ch in self.buffer.line_left ().chars () {
-			self.col_idx = if ch == '\t' {
-				self.col_idx - self.col_idx % self.tab_size + self.tab_size
-			} else {
-				self.col_idx + 1
-			};
-		}
+		self.col_idx = self.buffer.line_left ().graphemes (true)
+			.fold (0, |col_idx, cluster| self.next_col (col_idx, cluster));
 		self.saved_col_idx = self.col_idx;
 	}
 
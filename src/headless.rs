@@ -0,0 +1,120 @@
+use std::collections::VecDeque;
+use std::io::{ self, Write };
+
+use crate::config::*;
+use crate::misc::*;
+use crate::terminal::*;
+
+/// Terminal backend with scripted input and a virtual screen, for tests
+pub struct Headless {
+	screen: Screen,
+	events: VecDeque <InputEvent>,
+	started: bool,
+	clipboard: String,
+	title: String,
+	cursor_shape: CursorShape,
+}
+
+impl Headless {
+
+	pub fn new (rows: usize, cols: usize, events: impl IntoIterator <Item = InputEvent>) -> Self {
+		Self {
+			screen: Screen::new (rows, cols),
+			events: events.into_iter ().collect (),
+			started: false,
+			clipboard: String::new (),
+			title: String::new (),
+			cursor_shape: CursorShape::Default,
+		}
+	}
+
+	pub fn screen (& self) -> & Screen {
+		& self.screen
+	}
+
+	pub fn started (& self) -> bool {
+		self.started
+	}
+
+	pub fn clipboard (& self) -> & str {
+		& self.clipboard
+	}
+
+	pub fn title (& self) -> & str {
+		& self.title
+	}
+
+	pub fn cursor_shape (& self) -> CursorShape {
+		self.cursor_shape
+	}
+
+}
+
+impl Backend for Headless {
+
+	fn start (& mut self) -> GenResult <()> {
+		assert! (! self.started);
+		self.started = true;
+		Ok (())
+	}
+
+	fn stop (& mut self) -> GenResult <()> {
+		assert! (self.started);
+		self.started = false;
+		Ok (())
+	}
+
+	fn size (& self) -> Option <(usize, usize)> {
+		Some ((self.screen.rows (), self.screen.cols ()))
+	}
+
+	fn request_size (& mut self) -> GenResult <()> {
+		Ok (())
+	}
+
+	fn resize (& mut self, rows: usize, cols: usize) {
+		self.screen = Screen::new (rows, cols);
+	}
+
+	fn input (& mut self) -> GenResult <Option <InputEvent>> {
+		Ok (self.events.pop_front ())
+	}
+
+	fn set_clipboard (& mut self, text: & str) -> GenResult <()> {
+		self.clipboard = text.to_owned ();
+		Ok (())
+	}
+
+	fn request_clipboard (& mut self) -> GenResult <()> {
+		self.events.push_front (InputEvent::Clipboard (self.clipboard.clone ()));
+		Ok (())
+	}
+
+	fn set_title (& mut self, title: & str) -> GenResult <()> {
+		self.title = title.to_owned ();
+		Ok (())
+	}
+
+	fn set_cursor_shape (& mut self, shape: CursorShape) -> GenResult <()> {
+		self.cursor_shape = shape;
+		Ok (())
+	}
+
+	fn screen_mut (& mut self) -> & mut Screen {
+		& mut self.screen
+	}
+
+}
+
+impl Write for Headless {
+
+	fn write (& mut self, bytes: & [u8]) -> io::Result <usize> {
+		self.screen.put_str (& String::from_utf8_lossy (bytes));
+		Ok (bytes.len ())
+	}
+
+	fn flush (& mut self) -> io::Result <()> {
+		Ok (())
+	}
+
+}
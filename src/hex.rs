@@ -0,0 +1,334 @@
+use memmap2::Mmap;
+use std::ops::Deref;
+use std::str;
+use std::sync::Arc;
+
+use crate::*;
+use crate::encoding::*;
+use crate::misc::*;
+use crate::terminal::*;
+
+/// Number of bytes shown on each row
+const ROW_BYTES: usize = 16;
+
+/// Column where the hex bytes start, after the offset
+const HEX_COL: usize = 10;
+
+/// Column where the ASCII pane starts, after the hex bytes
+const ASCII_COL: usize = HEX_COL + ROW_BYTES * 3 + 2;
+
+/// Bytes at the start of a file which are looked at to decide if it is binary
+const BINARY_SAMPLE: usize = 1 << 20;
+
+/// Bytes of a binary file, shown as a hex dump with an offset column and an ASCII pane
+pub struct HexBuffer {
+	data: HexData,
+	pos: usize,
+	low_nibble: bool,
+	ascii: bool,
+	row_offset: usize,
+	undo: Vec <HexEdit>,
+	redo: Vec <HexEdit>,
+}
+
+/// Bytes being edited, which refer to a memory mapped file until they are first changed
+pub enum HexData {
+	Owned (Vec <u8>),
+	Mapped (Arc <Mmap>),
+}
+
+/// Replacement of some bytes with others, which is undone by swapping them back
+struct HexEdit {
+	offset: usize,
+	old: Vec <u8>,
+	new: Vec <u8>,
+}
+
+/// Check if the start of some data looks like a binary file rather than text
+pub fn is_binary (data: & [u8]) -> bool {
+	if let Some (encoding) = Encoding::sniff_bom (data).filter (|encoding| encoding.charset != Charset::Utf8) {
+		let data = & data [encoding.bom_len () .. ];
+		let (sample, _) = sample (data);
+		let text = if sample.len () < data.len () {
+			encoding.decode_utf16_prefix (sample)
+		} else {
+			encoding.decode (sample).ok ()
+		};
+		return text.is_none_or (|text| text.contains ('\0'));
+	}
+	let (sample, valid) = sample (data);
+	sample.contains (& 0) || ! valid && sample.iter ()
+		.any (|& by| matches! (by, 0x01 ..= 0x08 | 0x0e ..= 0x1a | 0x1c ..= 0x1f | 0x7f))
+}
+
+/// Start of some data which is looked at to work out what it is, and whether it is valid UTF-8
+pub fn sample (data: & [u8]) -> (& [u8], bool) {
+	let sample = & data [ .. data.len ().min (BINARY_SAMPLE)];
+	// a character may be cut off at the end of the sample
+	let valid = str::from_utf8 (sample).map_or_else (|err| err.error_len ().is_none (), |_| true);
+	(sample, valid)
+}
+
+impl HexBuffer {
+
+	pub fn new (data: HexData) -> Self {
+		Self {
+			data,
+			pos: 0,
+			low_nibble: false,
+			ascii: false,
+			row_offset: 0,
+			undo: Vec::new (),
+			redo: Vec::new (),
+		}
+	}
+
+	pub fn data (& self) -> & [u8] {
+		& self.data
+	}
+
+	/// Copy bytes which refer to a mapped file, keeping only those within the given size
+	pub fn unmap (& mut self, size: usize) {
+		let HexData::Mapped (ref map) = self.data else { return };
+		let truncated = size < map.len ();
+		self.data = HexData::Owned (map [ .. size.min (map.len ())].to_vec ());
+		if truncated {
+			self.move_to (self.pos);
+			self.undo.clear ();
+			self.redo.clear ();
+		}
+	}
+
+	/// Type a hex digit or, in the ASCII pane, a character, returning true if anything changed
+	pub fn type_char (& mut self, ch: char, overwrite: bool) -> bool {
+		if ch == '\t' {
+			self.ascii = ! self.ascii;
+			self.low_nibble = false;
+			return false;
+		}
+		if self.ascii {
+			let mut buf = [0; 4];
+			let bytes = ch.encode_utf8 (& mut buf).as_bytes ();
+			let len = if overwrite { bytes.len ().min (self.data.len () - self.pos) } else { 0 };
+			self.replace (self.pos, len, bytes.to_vec ());
+			self.pos += bytes.len ();
+			return true;
+		}
+		let nibble = some_or! (ch.to_digit (16), return false) as u8;
+		if self.low_nibble {
+			let by = (self.data [self.pos] & 0xf0) | nibble;
+			match self.undo.last_mut () {
+				Some (edit) if edit.offset == self.pos && edit.new.len () == 1 => {
+					self.data.make_mut () [self.pos] = by;
+					edit.new [0] = by;
+				},
+				_ => self.replace (self.pos, 1, vec! [ by ]),
+			}
+			self.pos += 1;
+			self.low_nibble = false;
+		} else {
+			let replace = overwrite && self.pos < self.data.len ();
+			let low = if replace { self.data [self.pos] & 0x0f } else { 0 };
+			self.replace (self.pos, replace as usize, vec! [ nibble << 4 | low ]);
+			self.low_nibble = true;
+		}
+		true
+	}
+
+	/// Insert the bytes of some text at the cursor
+	pub fn paste (& mut self, text: & str) -> bool {
+		if text.is_empty () { return false }
+		self.replace (self.pos, 0, text.as_bytes ().to_vec ());
+		self.pos += text.len ();
+		self.low_nibble = false;
+		true
+	}
+
+	pub fn delete (& mut self) -> bool {
+		if self.data.len () <= self.pos { return false }
+		self.replace (self.pos, 1, Vec::new ());
+		self.low_nibble = false;
+		true
+	}
+
+	pub fn backspace (& mut self) -> bool {
+		if self.low_nibble {
+			self.low_nibble = false;
+			return false;
+		}
+		if self.pos == 0 { return false }
+		self.pos -= 1;
+		self.replace (self.pos, 1, Vec::new ());
+		true
+	}
+
+	pub fn undo (& mut self) -> bool {
+		let edit = some_or! (self.undo.pop (), return false);
+		let edit = self.apply (edit);
+		self.redo.push (edit);
+		true
+	}
+
+	pub fn redo (& mut self) -> bool {
+		let edit = some_or! (self.redo.pop (), return false);
+		let edit = self.apply (edit);
+		self.undo.push (edit);
+		true
+	}
+
+	pub fn up (& mut self, num: usize) {
+		self.move_to (self.pos.saturating_sub (num * ROW_BYTES));
+	}
+
+	pub fn down (& mut self, num: usize) {
+		let pos = self.pos + num * ROW_BYTES;
+		self.move_to (if pos <= self.data.len () { pos } else { self.pos });
+	}
+
+	pub fn left (& mut self, num: usize) {
+		self.move_to (self.pos.saturating_sub (num));
+	}
+
+	pub fn right (& mut self, num: usize) {
+		self.move_to (self.pos + num);
+	}
+
+	pub fn home (& mut self) {
+		self.move_to (self.pos - self.pos % ROW_BYTES);
+	}
+
+	pub fn end (& mut self) {
+		self.move_to (self.pos - self.pos % ROW_BYTES + ROW_BYTES - 1);
+	}
+
+	/// Move to a byte offset, or the end if it is past it
+	pub fn move_to (& mut self, offset: usize) {
+		self.pos = offset.min (self.data.len ());
+		self.low_nibble = false;
+	}
+
+	pub fn cancel (& mut self) {
+		self.low_nibble = false;
+	}
+
+	pub fn mouse_press (& mut self, row: usize, col: usize) {
+		let idx = if ASCII_COL <= col {
+			self.ascii = true;
+			col - ASCII_COL
+		} else if HEX_COL <= col {
+			self.ascii = false;
+			let col = col - HEX_COL;
+			if col < ROW_BYTES / 2 * 3 { col / 3 } else { (col - 1) / 3 }
+		} else { 0 };
+		self.move_to ((self.row_offset + row) * ROW_BYTES + idx.min (ROW_BYTES - 1));
+	}
+
+	pub fn scroll (& mut self, delta: isize, rows: usize) {
+		let max_offset = self.data.len () / ROW_BYTES;
+		self.row_offset = self.row_offset.saturating_add_signed (delta).min (max_offset);
+		let row = self.pos / ROW_BYTES;
+		if row < self.row_offset {
+			self.move_to (self.row_offset * ROW_BYTES + self.pos % ROW_BYTES);
+		} else if self.row_offset + rows <= row {
+			self.move_to ((self.row_offset + rows).saturating_sub (1) * ROW_BYTES + self.pos % ROW_BYTES);
+		}
+	}
+
+	pub fn draw <Term: Backend> (
+		& mut self,
+		term: & mut Term,
+		ui_attrs: & UiAttrs,
+		start: usize,
+		end: usize,
+	) -> GenResult <()> {
+		let row = self.pos / ROW_BYTES;
+		if row < self.row_offset { self.row_offset = row; }
+		if self.row_offset + end - start - 1 < row { self.row_offset = row - (end - start - 1); }
+		for row_idx in start .. end {
+			term.move_to (row_idx, 0) ?;
+			let offset = (self.row_offset + row_idx - start) * ROW_BYTES;
+			if offset <= self.data.len () {
+				let bytes = & self.data [offset .. (offset + ROW_BYTES).min (self.data.len ())];
+				term.text_attr (ui_attrs.line_nums) ?;
+				write! (term, "{offset:08x}  ") ?;
+				for idx in 0 .. ROW_BYTES {
+					term.text_attr (self.byte_attr (ui_attrs, offset + idx, ! self.ascii)) ?;
+					match bytes.get (idx) {
+						Some (by) => write! (term, "{by:02x}") ?,
+						None => write! (term, "  ") ?,
+					}
+					term.text_attr (ui_attrs.default) ?;
+					write! (term, "{}", if idx + 1 == ROW_BYTES / 2 { "  " } else { " " }) ?;
+				}
+				write! (term, " ") ?;
+				for (idx, & by) in bytes.iter ().enumerate () {
+					let printable = by == b' ' || by.is_ascii_graphic ();
+					let attr = self.byte_attr (ui_attrs, offset + idx, self.ascii);
+					term.text_attr (if printable || attr != ui_attrs.default { attr } else { ui_attrs.control }) ?;
+					write! (term, "{}", if printable { by as char } else { '.' }) ?;
+				}
+				term.text_attr (ui_attrs.default) ?;
+			}
+			term.clear_to_end () ?;
+		}
+		let idx = self.pos % ROW_BYTES;
+		let col = if self.ascii {
+			ASCII_COL + idx
+		} else {
+			HEX_COL + idx * 3 + (ROW_BYTES / 2 <= idx) as usize + self.low_nibble as usize
+		};
+		term.move_to (start + row - self.row_offset, col) ?;
+		Ok (())
+	}
+
+	pub fn status (& self) -> String {
+		format! ("offset {pos:#x}/{len:#x}  hex", pos = self.pos, len = self.data.len ())
+	}
+
+	/// Attribute for a byte, highlighting the one under the cursor in the inactive pane
+	fn byte_attr (& self, ui_attrs: & UiAttrs, offset: usize, active: bool) -> TextAttr {
+		if offset == self.pos && ! active { ui_attrs.selection } else { ui_attrs.default }
+	}
+
+	/// Replace some bytes, recording how to undo it
+	fn replace (& mut self, offset: usize, len: usize, new: Vec <u8>) {
+		let old = self.data.make_mut ().splice (offset .. offset + len, new.iter ().copied ()).collect ();
+		self.undo.push (HexEdit { offset, old, new });
+		self.redo.clear ();
+	}
+
+	/// Put back the old bytes of an edit, returning the edit which reverses it
+	fn apply (& mut self, edit: HexEdit) -> HexEdit {
+		let HexEdit { offset, old, new } = edit;
+		let len = old.len ();
+		let removed = self.data.make_mut ().splice (offset .. offset + new.len (), old).collect ();
+		self.pos = offset;
+		self.low_nibble = false;
+		HexEdit { offset, old: removed, new: self.data [offset .. offset + len].to_vec () }
+	}
+
+}
+
+impl HexData {
+
+	fn make_mut (& mut self) -> & mut Vec <u8> {
+		match * self {
+			Self::Owned (ref mut data) => data,
+			Self::Mapped (ref map) => {
+				* self = Self::Owned (map.to_vec ());
+				if let Self::Owned (ref mut data) = * self { data } else { unreachable! () }
+			},
+		}
+	}
+
+}
+
+impl Deref for HexData {
+	type Target = [u8];
+	fn deref (& self) -> & [u8] {
+		match * self {
+			Self::Owned (ref data) => data,
+			Self::Mapped (ref map) => map,
+		}
+	}
+}
@@ -1,26 +1,46 @@
+use memmap2::Mmap;
 use std::ops::Deref;
 use std::rc::Rc;
+use std::str;
+use std::sync::Arc;
 
 pub enum Line {
 	Owned (String),
 	Shared (Rc <String>, usize, usize),
+
+	/// Part of a memory mapped file, which has already been checked to be valid UTF-8
+	Mapped (Arc <Mmap>, usize, usize),
 }
 
 impl Line {
 
 	pub fn as_str (& self) -> & str {
-		match self {
-			& Self::Owned (ref val) => val,
-			& Self::Shared (ref val, start, end) => & val [start .. end],
+		match * self {
+			Self::Owned (ref val) => val,
+			Self::Shared (ref val, start, end) => & val [start .. end],
+			// another process may rewrite the file, so the editor must call `unmap` as soon as it
+			// changes on disk, and can't rely on this check from when the line was found
+			Self::Mapped (ref map, start, end) => unsafe {
+				str::from_utf8_unchecked (& map [start .. end])
+			},
 		}
 	}
 
+	/// Copy a mapped line, reading only what is within the given size, and check it is still UTF-8
+	pub fn unmap (& mut self, size: usize) -> bool {
+		let Self::Mapped (ref map, start, end) = * self else { return true };
+		let bytes = & map [start.min (size) .. end.min (size)];
+		let valid = str::from_utf8 (bytes).is_ok ();
+		* self = Self::Owned (String::from_utf8_lossy (bytes).into_owned ());
+		valid
+	}
+
 	pub fn make_mut (& mut self) -> & mut String {
-		match self {
-			& mut Self::Owned (ref mut val) => val,
-			& mut Self::Shared (ref val, start, end) => {
-				* self = Self::Owned (val [start .. end].to_owned ());
-				if let & mut Self::Owned (ref mut val) = self { val } else { unreachable! () }
+		match * self {
+			Self::Owned (ref mut val) => val,
+			Self::Shared (..) | Self::Mapped (..) => {
+				* self = Self::Owned (self.as_str ().to_owned ());
+				if let Self::Owned (ref mut val) = * self { val } else { unreachable! () }
 			},
 		}
 	}
@@ -1,43 +1,133 @@
 use std::env;
-use std::panic;
+use std::io::{ self, IsTerminal as _ };
+use std::panic::{ self, AssertUnwindSafe };
 use std::process::ExitCode;
 
 mod config;
 mod buffer;
 mod editor;
+mod encoding;
 mod file;
+mod hex;
 mod line;
 mod misc;
+mod rope;
 mod terminal;
 
+#[ cfg (test) ]
+mod headless;
+
+use crate::config::*;
 use crate::editor::*;
 use crate::file::*;
 use crate::misc::*;
+use crate::terminal::*;
 
 fn main () -> ExitCode {
-	match panic::catch_unwind (|| -> GenResult <()> {
-		let files: Vec <File> =
-			env::args ().skip (1)
-				.map (|filename| File::load (filename.into ()))
-				.collect::<GenResult <_>> () ?;
-		let mut editor = Editor::new (files) ?;
-		editor.run () ?;
-		Ok (())
-	}) {
-		Ok (Ok (())) => ExitCode::SUCCESS,
+	let default_hook = panic::take_hook ();
+	panic::set_hook (Box::new (move |info| {
+		terminal::restore_terminal ();
+		default_hook (info);
+	}));
+	let config = match Config::load () {
+		Ok (config) => config,
+		Err (err) => {
+			eprintln! ("Error: {err}");
+			return ExitCode::FAILURE;
+		},
+	};
+	let Args { files, stdout } = match Args::parse (& config) {
+		Ok (args) => args,
+		Err (err) => {
+			eprintln! ("Error: {err}");
+			return ExitCode::FAILURE;
+		},
+	};
+	match panic::catch_unwind (AssertUnwindSafe (|| -> GenResult <()> {
+		let term = Terminal::new (& config.misc) ?;
+		let mut editor = Editor::new (term, config, files.clone ()) ?;
+		editor.run ()
+	})) {
+		Ok (Ok (())) => match stdout.map (|file| file.write_data (& mut io::stdout ().lock ())) {
+			Some (Err (err)) => {
+				eprintln! ("Error writing to stdout: {err}");
+				rescue (& files);
+				ExitCode::FAILURE
+			},
+			_ => ExitCode::SUCCESS,
+		},
 		Ok (Err (err)) => {
 			eprintln! ("Error: {err}");
+			rescue (& files);
 			ExitCode::FAILURE
 		},
-		Err (panic) => {
-			match panic.downcast::<String> () {
-				Ok (panic) => eprintln! ("Panic: {panic}"),
-				Err (panic) => match panic.downcast::<& str> () {
-					Ok (panic) => eprintln! ("Panic: {panic}"),
-					Err (panic) => eprintln! ("Panic: {:?}", panic.type_id ()),
-				},
-			}
+		Err (_) => {
+			rescue (& files);
 			ExitCode::FAILURE
 		},
 	}
 }
+
+struct Args {
+	files: Vec <File>,
+
+	/// The buffer read from standard input, to write to standard output on exit
+	stdout: Option <File>,
+}
+
+impl Args {
+
+	fn parse (config: & Config) -> GenResult <Self> {
+		let mut names = Vec::new ();
+		let mut stdout = false;
+		let mut hex = false;
+		let mut options = true;
+		for arg in env::args ().skip (1) {
+			match arg.as_str () {
+				"--" if options => options = false,
+				"--stdout" if options => stdout = true,
+				"--hex" if options => hex = true,
+				"-" => names.push (arg),
+				_ if options && arg.starts_with ('-') => return Err (format! ("Invalid option: {arg}").into ()),
+				_ => names.push (arg),
+			}
+		}
+		if names.is_empty () && ! io::stdin ().is_terminal () {
+			names.push ("-".to_owned ());
+		}
+		if names.is_empty () {
+			return Err ("Usage: jtx [--hex] [--stdout] FILE|- ...".into ());
+		}
+		let mut files = Vec::new ();
+		let mut stdin_file = None;
+		for name in names {
+			if name == "-" {
+				if stdin_file.is_some () { return Err ("Standard input can only be read once".into ()) }
+				let file = File::read_stdin (hex) ?;
+				file.set_file_type (config.default_file_type ());
+				stdin_file = Some (file.clone ());
+				files.push (file);
+				continue;
+			}
+			let file = File::load (name.into (), config.misc.large_file_size, hex) ?;
+			file.set_file_type (config.file_type (& file.name ()));
+			files.push (file);
+		}
+		if stdout && stdin_file.is_none () {
+			return Err ("--stdout needs - as one of the files".into ());
+		}
+		Ok (Self { files, stdout: stdin_file.filter (|_| stdout) })
+	}
+
+}
+
+/// Save any dirty buffers to recovery files, after the editor has failed
+fn rescue (files: & [File]) {
+	for file in files {
+		match file.emergency_save () {
+			Ok (Some (path)) => eprintln! ("Saved {name} to {path}", name = file.name ()),
+			Ok (None) => (),
+			Err (err) => eprintln! ("Failed to save {name}: {err}", name = file.name ()),
+		}
+	}
+}
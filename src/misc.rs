@@ -1,5 +1,7 @@
 use std::error::Error;
 
+use unicode_width::UnicodeWidthStr as _;
+
 pub type GenError = Box <dyn Error>;
 pub type GenResult <Val> = Result <Val, GenError>;
 
@@ -12,3 +14,63 @@ macro_rules! some_or {
 		}
 	}
 }
+
+const BASE64_CHARS: & [u8; 64] =
+	b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+pub fn base64_encode (src: & [u8]) -> String {
+	let mut result = String::with_capacity (src.len ().div_ceil (3) * 4);
+	for chunk in src.chunks (3) {
+		let val = chunk.iter ().enumerate ()
+			.fold (0_u32, |val, (idx, & by)| val | (by as u32) << (16 - idx * 8));
+		for idx in 0 .. 4 {
+			if idx <= chunk.len () {
+				result.push (BASE64_CHARS [(val >> (18 - idx * 6)) as usize & 0x3f] as char);
+			} else {
+				result.push ('=');
+			}
+		}
+	}
+	result
+}
+
+pub fn base64_decode (src: & str) -> Option <Vec <u8>> {
+	let src = src.trim_end_matches ('=');
+	let mut result = Vec::with_capacity (src.len () * 3 / 4);
+	let mut val = 0_u32;
+	let mut bits = 0;
+	for by in src.bytes () {
+		let digit = BASE64_CHARS.iter ().position (|& ch| ch == by) ? as u32;
+		val = (val << 6) | digit;
+		bits += 6;
+		if 8 <= bits {
+			bits -= 8;
+			result.push ((val >> bits) as u8);
+		}
+	}
+	Some (result)
+}
+
+/// Number of terminal columns taken by a grapheme cluster, which is always one or two
+pub fn cluster_width (cluster: & str) -> usize {
+	if let Some (name) = control_name (cluster) { return name.len () }
+	cluster.width ().clamp (1, 2)
+}
+
+/// Visible form of a grapheme cluster containing control characters, such as `^[` or `<U+0085>`
+pub fn control_name (cluster: & str) -> Option <String> {
+	fn char_name (ch: char) -> Option <String> {
+		match ch {
+			'\x00' ..= '\x1f' => Some (format! ("^{}", (ch as u8 + b'@') as char)),
+			'\x7f' => Some ("^?".to_owned ()),
+			'\u{202a}' ..= '\u{202e}' | '\u{2066}' ..= '\u{2069}' =>
+				Some (format! ("<U+{:04X}>", ch as u32)),
+			_ if ch.is_control () => Some (format! ("<U+{:04X}>", ch as u32)),
+			_ => None,
+		}
+	}
+	if cluster.chars ().all (|ch| char_name (ch).is_none ()) { return None }
+	Some (cluster.chars ()
+		.map (|ch| char_name (ch).unwrap_or_else (|| ch.to_string ()))
+		.collect ())
+}
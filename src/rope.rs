@@ -0,0 +1,546 @@
+use std::iter;
+use std::mem;
+use std::ops::{ Add, AddAssign, Range, Sub, SubAssign };
+use std::slice;
+
+use crate::line::*;
+use crate::some_or;
+
+/// Most lines in a leaf, or children in a branch, before it is split
+const MAX_LEN: usize = 64;
+
+/// Fewest lines in a leaf, or children in a branch, before it is merged with a neighbour
+const MIN_LEN: usize = MAX_LEN / 4;
+
+/// Balanced tree of lines, counting the lines, bytes and chars in each subtree
+pub struct Rope {
+	root: Node,
+	summary: Summary,
+}
+
+#[ derive (Clone, Copy, Debug, Default, Eq, PartialEq) ]
+pub struct Summary {
+	pub lines: usize,
+	pub bytes: usize,
+	pub chars: usize,
+}
+
+enum Node {
+	/// Lines with their counts, so that lookups never need to scan their text
+	Leaf (Vec <(Summary, Line)>),
+	Branch (Vec <(Summary, Node)>),
+}
+
+impl Rope {
+
+	pub fn from_lines (lines: Vec <Line>) -> Self {
+		let lines = lines.into_iter ().map (|line| (Summary::of_line (& line), line)).collect ();
+		let mut rope = Self { root: Node::Leaf (lines), summary: Summary::default () };
+		let extra = rope.root.split_chunks ();
+		rope.grow (extra);
+		rope.summary = rope.root.summary ();
+		rope
+	}
+
+	pub fn len (& self) -> usize {
+		self.summary.lines
+	}
+
+	pub fn is_empty (& self) -> bool {
+		self.summary.lines == 0
+	}
+
+	pub fn summary (& self) -> Summary {
+		self.summary
+	}
+
+	pub fn line (& self, mut line_idx: usize) -> & Line {
+		assert! (line_idx < self.len ());
+		let mut node = & self.root;
+		loop {
+			match * node {
+				Node::Leaf (ref lines) => return & lines [line_idx].1,
+				Node::Branch (ref children) => {
+					let child_idx = Node::find_child (children, & mut line_idx, |summary| summary.lines);
+					node = & children [child_idx].1;
+				},
+			}
+		}
+	}
+
+	/// Change a line in place, keeping the counts up to date
+	pub fn edit <Ret> (& mut self, line_idx: usize, edit_fn: impl FnOnce (& mut String) -> Ret) -> Ret {
+		assert! (line_idx < self.len ());
+		let (ret, old, new) = self.root.edit (line_idx, edit_fn);
+		self.summary -= old;
+		self.summary += new;
+		ret
+	}
+
+	/// Change every line in place, keeping the counts up to date
+	pub fn edit_all (& mut self, mut edit_fn: impl FnMut (& mut Line)) {
+		self.summary = self.root.edit_all (& mut edit_fn);
+	}
+
+	/// Replace a range of lines with new ones
+	pub fn splice (& mut self, range: Range <usize>, lines: impl IntoIterator <Item = Line>) {
+		assert! (range.start <= range.end && range.end <= self.len ());
+		if range.start < range.end {
+			self.summary -= self.root.remove (range.clone ());
+			self.shrink ();
+		}
+		let lines: Vec <(Summary, Line)> = lines.into_iter ()
+			.map (|line| (Summary::of_line (& line), line))
+			.collect ();
+		if lines.is_empty () { return }
+		let added = lines.iter ().map (|& (summary, _)| summary).sum ();
+		let extra = self.root.insert (range.start, lines, added);
+		self.summary += added;
+		self.grow (extra);
+	}
+
+	/// Iterate over the lines starting at the given index
+	pub fn iter_from (& self, mut line_idx: usize) -> Iter <'_> {
+		let mut stack = Vec::new ();
+		if self.len () <= line_idx { return Iter { stack, leaf: [].iter () } }
+		let mut node = & self.root;
+		loop {
+			match * node {
+				Node::Leaf (ref lines) => return Iter { stack, leaf: lines [line_idx .. ].iter () },
+				Node::Branch (ref children) => {
+					let child_idx = Node::find_child (children, & mut line_idx, |summary| summary.lines);
+					stack.push (children [child_idx + 1 .. ].iter ());
+					node = & children [child_idx].1;
+				},
+			}
+		}
+	}
+
+	/// Byte offset of the start of a line
+	pub fn line_to_offset (& self, line_idx: usize) -> usize {
+		self.line_start (line_idx).bytes
+	}
+
+	/// Char offset of the start of a line
+	pub fn line_to_char (& self, line_idx: usize) -> usize {
+		self.line_start (line_idx).chars
+	}
+
+	/// Line containing a byte offset, and the remaining offset within that line
+	pub fn offset_to_line (& self, offset: usize) -> (usize, usize) {
+		self.find_line (offset, |summary| summary.bytes)
+	}
+
+	/// Line containing a char offset, and the remaining chars within that line
+	pub fn char_to_line (& self, char_idx: usize) -> (usize, usize) {
+		self.find_line (char_idx, |summary| summary.chars)
+	}
+
+	/// Counts for all the lines before the given one
+	fn line_start (& self, mut line_idx: usize) -> Summary {
+		assert! (line_idx <= self.len ());
+		if line_idx == self.len () { return self.summary }
+		let mut start = Summary::default ();
+		let mut node = & self.root;
+		loop {
+			match * node {
+				Node::Leaf (ref lines) =>
+					return start + lines [ .. line_idx].iter ().map (|& (summary, _)| summary).sum (),
+				Node::Branch (ref children) => {
+					let child_idx = Node::find_child (children, & mut line_idx, |summary| summary.lines);
+					start += children [ .. child_idx].iter ().map (|& (summary, _)| summary).sum ();
+					node = & children [child_idx].1;
+				},
+			}
+		}
+	}
+
+	/// Line containing a position in some metric, and the position within it, or the end if past it
+	fn find_line (& self, mut pos: usize, metric: fn (& Summary) -> usize) -> (usize, usize) {
+		if self.is_empty () { return (0, 0) }
+		if metric (& self.summary) <= pos {
+			let last = self.len () - 1;
+			return (last, metric (& (self.summary - self.line_start (last))) - 1);
+		}
+		let mut line_idx = 0;
+		let mut node = & self.root;
+		loop {
+			match * node {
+				Node::Leaf (ref lines) => {
+					for (summary, _) in lines {
+						if pos < metric (summary) { return (line_idx, pos) }
+						pos -= metric (summary);
+						line_idx += 1;
+					}
+					unreachable! ();
+				},
+				Node::Branch (ref children) => {
+					let child_idx = Node::find_child (children, & mut pos, metric);
+					line_idx += children [ .. child_idx].iter ().map (|(summary, _)| summary.lines).sum::<usize> ();
+					node = & children [child_idx].1;
+				},
+			}
+		}
+	}
+
+	/// Add a level above the root while it has overflowed into extra siblings
+	fn grow (& mut self, mut extra: Vec <Node>) {
+		while ! extra.is_empty () {
+			let old_root = mem::replace (& mut self.root, Node::Leaf (Vec::new ()));
+			self.root = Node::Branch (iter::once (old_root).chain (extra)
+				.map (|node| (node.summary (), node))
+				.collect ());
+			extra = self.root.split_chunks ();
+		}
+	}
+
+	/// Remove levels above the root while it has a single child
+	fn shrink (& mut self) {
+		while let Node::Branch (ref mut children) = self.root {
+			if 1 < children.len () { break }
+			self.root = match children.pop () {
+				Some ((_, child)) => child,
+				None => Node::Leaf (Vec::new ()),
+			};
+		}
+	}
+
+}
+
+impl Node {
+
+	fn len (& self) -> usize {
+		match * self {
+			Self::Leaf (ref lines) => lines.len (),
+			Self::Branch (ref children) => children.len (),
+		}
+	}
+
+	fn summary (& self) -> Summary {
+		match * self {
+			Self::Leaf (ref lines) => lines.iter ().map (|& (summary, _)| summary).sum (),
+			Self::Branch (ref children) => children.iter ().map (|& (summary, _)| summary).sum (),
+		}
+	}
+
+	/// Find the child containing a position, and make the position relative to that child
+	fn find_child (
+		children: & [(Summary, Node)],
+		pos: & mut usize,
+		metric: fn (& Summary) -> usize,
+	) -> usize {
+		for (child_idx, (summary, _)) in children.iter ().enumerate () {
+			if * pos < metric (summary) || child_idx + 1 == children.len () { return child_idx }
+			* pos -= metric (summary);
+		}
+		unreachable! ();
+	}
+
+	fn edit <Ret> (
+		& mut self,
+		mut line_idx: usize,
+		edit_fn: impl FnOnce (& mut String) -> Ret,
+	) -> (Ret, Summary, Summary) {
+		match * self {
+			Self::Leaf (ref mut lines) => {
+				let (ref mut summary, ref mut line) = lines [line_idx];
+				let old = * summary;
+				let ret = edit_fn (line.make_mut ());
+				* summary = Summary::of_line (line);
+				(ret, old, * summary)
+			},
+			Self::Branch (ref mut children) => {
+				let child_idx = Self::find_child (children, & mut line_idx, |summary| summary.lines);
+				let (ref mut summary, ref mut child) = children [child_idx];
+				let (ret, old, new) = child.edit (line_idx, edit_fn);
+				* summary -= old;
+				* summary += new;
+				(ret, old, new)
+			},
+		}
+	}
+
+	fn edit_all (& mut self, edit_fn: & mut impl FnMut (& mut Line)) -> Summary {
+		match * self {
+			Self::Leaf (ref mut lines) => lines.iter_mut ()
+				.map (|& mut (ref mut summary, ref mut line)| {
+					edit_fn (line);
+					* summary = Summary::of_line (line);
+					* summary
+				})
+				.sum (),
+			Self::Branch (ref mut children) => children.iter_mut ()
+				.map (|& mut (ref mut summary, ref mut child)| {
+					* summary = child.edit_all (edit_fn);
+					* summary
+				})
+				.sum (),
+		}
+	}
+
+	/// Insert lines before the given index, returning any extra siblings if this node overflows
+	fn insert (& mut self, mut line_idx: usize, lines: Vec <(Summary, Line)>, added: Summary) -> Vec <Self> {
+		match * self {
+			Self::Leaf (ref mut leaf_lines) => {
+				leaf_lines.splice (line_idx .. line_idx, lines);
+			},
+			Self::Branch (ref mut children) => {
+				let child_idx = Self::find_child (children, & mut line_idx, |summary| summary.lines);
+				let (ref mut summary, ref mut child) = children [child_idx];
+				let extra = child.insert (line_idx, lines, added);
+				if extra.is_empty () {
+					* summary += added;
+				} else {
+					* summary = child.summary ();
+					children.splice (child_idx + 1 .. child_idx + 1,
+						extra.into_iter ().map (|node| (node.summary (), node)));
+				}
+			},
+		}
+		self.split_chunks ()
+	}
+
+	/// Remove a range of lines, returning the counts for what was removed
+	fn remove (& mut self, range: Range <usize>) -> Summary {
+		match * self {
+			Self::Leaf (ref mut lines) =>
+				lines.drain (range).map (|(summary, _)| summary).sum (),
+			Self::Branch (ref mut children) => {
+				let mut removed = Summary::default ();
+				let mut start = 0;
+				let mut child_idx = 0;
+				while child_idx < children.len () && start < range.end {
+					let end = start + children [child_idx].0.lines;
+					if end <= range.start {
+						child_idx += 1;
+					} else if range.start <= start && end <= range.end {
+						removed += children.remove (child_idx).0;
+					} else {
+						let (ref mut summary, ref mut child) = children [child_idx];
+						let child_range = range.start.max (start) - start .. range.end.min (end) - start;
+						let child_removed = child.remove (child_range);
+						* summary -= child_removed;
+						removed += child_removed;
+						child_idx += 1;
+					}
+					start = end;
+				}
+				Self::rebalance (children);
+				removed
+			},
+		}
+	}
+
+	/// Merge any children which have become too small into a neighbour
+	fn rebalance (children: & mut Vec <(Summary, Self)>) {
+		let mut child_idx = 0;
+		while child_idx < children.len () && 1 < children.len () {
+			if MIN_LEN <= children [child_idx].1.len () {
+				child_idx += 1;
+				continue;
+			}
+			let left_idx = if child_idx + 1 < children.len () { child_idx } else { child_idx - 1 };
+			let (right_summary, right) = children.remove (left_idx + 1);
+			let (ref mut summary, ref mut left) = children [left_idx];
+			left.append (right);
+			* summary += right_summary;
+			let extra = left.split_chunks ();
+			if ! extra.is_empty () {
+				* summary = left.summary ();
+				children.splice (left_idx + 1 .. left_idx + 1,
+					extra.into_iter ().map (|node| (node.summary (), node)));
+			}
+			child_idx = left_idx;
+		}
+	}
+
+	fn append (& mut self, other: Self) {
+		match (self, other) {
+			(& mut Self::Leaf (ref mut lines), Self::Leaf (other_lines)) =>
+				lines.extend (other_lines),
+			(& mut Self::Branch (ref mut children), Self::Branch (other_children)) =>
+				children.extend (other_children),
+			_ => unreachable! (),
+		}
+	}
+
+	fn split_off (& mut self, at: usize) -> Self {
+		match * self {
+			Self::Leaf (ref mut lines) => Self::Leaf (lines.split_off (at)),
+			Self::Branch (ref mut children) => Self::Branch (children.split_off (at)),
+		}
+	}
+
+	/// Split an overflowing node into evenly sized pieces, returning all but the first
+	fn split_chunks (& mut self) -> Vec <Self> {
+		let len = self.len ();
+		if len <= MAX_LEN { return Vec::new () }
+		let num_chunks = len.div_ceil (MAX_LEN);
+		let mut chunks: Vec <Self> = (1 .. num_chunks).rev ()
+			.map (|chunk_idx| self.split_off (chunk_idx * len / num_chunks))
+			.collect ();
+		chunks.reverse ();
+		chunks
+	}
+
+}
+
+impl Summary {
+
+	/// Counts for a single line, computed once when it is stored or edited
+	fn of_line (line: & Line) -> Self {
+		Self { lines: 1, bytes: line.len () + 1, chars: line.as_str ().chars ().count () + 1 }
+	}
+
+}
+
+impl Add for Summary {
+	type Output = Self;
+	fn add (self, other: Self) -> Self {
+		Self {
+			lines: self.lines + other.lines,
+			bytes: self.bytes + other.bytes,
+			chars: self.chars + other.chars,
+		}
+	}
+}
+
+impl AddAssign for Summary {
+	fn add_assign (& mut self, other: Self) {
+		* self = * self + other;
+	}
+}
+
+impl Sub for Summary {
+	type Output = Self;
+	fn sub (self, other: Self) -> Self {
+		Self {
+			lines: self.lines - other.lines,
+			bytes: self.bytes - other.bytes,
+			chars: self.chars - other.chars,
+		}
+	}
+}
+
+impl SubAssign for Summary {
+	fn sub_assign (& mut self, other: Self) {
+		* self = * self - other;
+	}
+}
+
+impl iter::Sum for Summary {
+	fn sum <Iter: Iterator <Item = Self>> (iter: Iter) -> Self {
+		iter.fold (Self::default (), Add::add)
+	}
+}
+
+pub struct Iter <'rope> {
+	stack: Vec <slice::Iter <'rope, (Summary, Node)>>,
+	leaf: slice::Iter <'rope, (Summary, Line)>,
+}
+
+impl <'rope> Iterator for Iter <'rope> {
+
+	type Item = & 'rope Line;
+
+	fn next (& mut self) -> Option <& 'rope Line> {
+		loop {
+			if let Some ((_, line)) = self.leaf.next () { return Some (line) }
+			let mut node = loop {
+				let children = self.stack.last_mut () ?;
+				match children.next () {
+					Some ((_, node)) => break node,
+					None => { self.stack.pop (); },
+				}
+			};
+			loop {
+				match * node {
+					Node::Leaf (ref lines) => {
+						self.leaf = lines.iter ();
+						break;
+					},
+					Node::Branch (ref children) => {
+						let mut children = children.iter ();
+						let (_, first) = some_or! (children.next (), break);
+						self.stack.push (children);
+						node = first;
+					},
+				}
+			}
+		}
+	}
+
+}
+
+#[ cfg (test) ]
+mod tests {
+
+	use super::*;
+
+	fn check (rope: & Rope, model: & [String]) {
+		assert_eq! (rope.len (), model.len ());
+		assert_eq! (rope.root.summary (), rope.summary);
+		let lines: Vec <& str> = rope.iter_from (0).map (Line::as_str).collect ();
+		assert_eq! (lines, model);
+		let mut bytes = 0;
+		let mut chars = 0;
+		for (line_idx, line) in model.iter ().enumerate () {
+			let line_chars = line.chars ().count ();
+			assert_eq! (rope.line (line_idx).as_str (), line);
+			assert_eq! (rope.line_to_offset (line_idx), bytes);
+			assert_eq! (rope.offset_to_line (bytes), (line_idx, 0));
+			assert_eq! (rope.offset_to_line (bytes + line.len ()), (line_idx, line.len ()));
+			assert_eq! (rope.line_to_char (line_idx), chars);
+			assert_eq! (rope.char_to_line (chars), (line_idx, 0));
+			assert_eq! (rope.char_to_line (chars + line_chars), (line_idx, line_chars));
+			bytes += line.len () + 1;
+			chars += line_chars + 1;
+		}
+		assert_eq! (rope.summary ().chars, chars);
+		if let Some (start) = model.len ().checked_sub (3) {
+			let lines: Vec <& str> = rope.iter_from (start).map (Line::as_str).collect ();
+			assert_eq! (lines, & model [start .. ]);
+		}
+	}
+
+	#[ test ]
+	fn matches_vec () {
+		let mut seed = 1_u64;
+		let mut random = |max: usize| {
+			seed = seed.wrapping_mul (6_364_136_223_846_793_005).wrapping_add (1);
+			(seed >> 33) as usize % max.max (1)
+		};
+		let mut model: Vec <String> = (0 .. 1000).map (|idx| format! ("line {idx} é")).collect ();
+		let mut rope = Rope::from_lines (model.iter ().map (|line| Line::from (line.as_str ())).collect ());
+		check (& rope, & model);
+		for step in 0 .. 300 {
+			let start = random (model.len () + 1);
+			let end = start + random (model.len () - start + 1).min (random (200));
+			let new: Vec <String> = (0 .. random (300)).map (|idx| format! ("{step}.{idx}")).collect ();
+			model.splice (start .. end, new.iter ().cloned ());
+			rope.splice (start .. end, new.iter ().map (|line| Line::from (line.as_str ())));
+			if ! model.is_empty () {
+				let line_idx = random (model.len ());
+				model [line_idx].push ('ü');
+				rope.edit (line_idx, |line| line.push ('ü'));
+			}
+			assert_eq! (rope.root.summary (), rope.summary);
+			if step % 30 == 0 { check (& rope, & model) }
+		}
+		check (& rope, & model);
+		rope.splice (0 .. model.len (), []);
+		check (& rope, & []);
+	}
+
+	#[ test ]
+	fn long_lines () {
+		let model: Vec <String> = (0 .. 200).map (|idx| "é".repeat (idx * 500)).collect ();
+		let mut rope = Rope::from_lines (model.iter ().map (|line| Line::from (line.as_str ())).collect ());
+		check (& rope, & model);
+		rope.edit (199, |line| line.push ('x'));
+		assert_eq! (rope.offset_to_line (rope.summary ().bytes - 2), (199, model [199].len ()));
+		assert_eq! (rope.char_to_line (rope.summary ().chars - 2), (199, 199 * 500));
+		assert_eq! (rope.char_to_line (usize::MAX), (199, 199 * 500 + 1));
+	}
+
+}
@@ -1,158 +1,545 @@
 use std::collections::HashMap;
-use std::io::{ self, Read as _, Stdin, Stdout, Write };
-use std::os::unix::io::AsRawFd as _;
+use std::fmt::Write as _;
+use std::fmt;
+use std::fs::{ File as FsFile, OpenOptions };
+use std::io::{ self, IsTerminal as _, Read as _, Write };
+use std::mem;
+use std::os::unix::io::{ AsFd as _, AsRawFd as _ };
+use std::ptr;
 use std::rc::Rc;
+use std::os::unix::io::RawFd;
+use std::sync::{ Mutex, OnceLock };
+use std::sync::atomic::{ AtomicBool, AtomicI32, Ordering };
+use std::time::Duration;
 use termios::Termios;
+use unicode_segmentation::UnicodeSegmentation as _;
+use unicode_width::UnicodeWidthChar as _;
 
 use crate::config::*;
 use crate::misc::*;
+use crate::some_or;
+
+static RESIZED: AtomicBool = AtomicBool::new (false);
+static TERMINATED: AtomicI32 = AtomicI32::new (0);
+
+/// Whether kitty keyboard enhancements were pushed, so they are only popped in that case
+static KEYBOARD_PUSHED: AtomicBool = AtomicBool::new (false);
+
+/// File descriptors and settings of a started terminal, so a panic hook can restore it
+static STARTED: Mutex <Option <(RawFd, RawFd, Termios)>> = Mutex::new (None);
+
+/// Pipe which other threads write to in order to wake up a blocking [`Terminal::input`]
+static WAKE_PIPE: OnceLock <(RawFd, RawFd)> = OnceLock::new ();
+
+const START_SEQUENCE: & str = concat! (
+	"\x1b[?1049h",
+	"\x1b[?2004h",
+	"\x1b[?1002h\x1b[?1006h",
+	"\x1b[?1004h",
+	"\x1b[?u",
+	"\x1b[?2026$p",
+	"\x1b[22;0t",
+);
+
+const STOP_SEQUENCE: & str = concat! (
+	"\x1b[0m\x1b[?25h\x1b[0 q",
+	"\x1b[23;0t",
+	"\x1b[?1004l",
+	"\x1b[?1006l\x1b[?1002l",
+	"\x1b[?2004l",
+	"\x1b[?1049l",
+);
+
+extern "C" fn handle_sigwinch (_signal: libc::c_int) {
+	RESIZED.store (true, Ordering::Relaxed);
+}
+
+extern "C" fn handle_terminate (signal: libc::c_int) {
+	TERMINATED.store (signal, Ordering::Relaxed);
+}
+
+/// Put a started terminal back into its original state, ignoring errors
+pub fn restore_terminal () {
+	let started = STARTED.lock ().unwrap_or_else (|err| err.into_inner ()).take ();
+	let (in_fd, out_fd, termios) = some_or! (started, return);
+	if KEYBOARD_PUSHED.swap (false, Ordering::Relaxed) {
+		unsafe { libc::write (out_fd, b"\x1b[<u".as_ptr () as * const libc::c_void, 4) };
+	}
+	unsafe {
+		libc::write (out_fd, STOP_SEQUENCE.as_ptr () as * const libc::c_void, STOP_SEQUENCE.len ());
+	}
+	termios::tcsetattr (in_fd, termios::TCSANOW, & termios).ok ();
+}
+
+/// Create the pipe used by [`wake`], if it doesn't exist yet, returning its read end
+fn open_wake_pipe () -> io::Result <RawFd> {
+	if let Some (& (read_fd, _)) = WAKE_PIPE.get () { return Ok (read_fd) }
+	let mut fds = [-1; 2];
+	if unsafe { libc::pipe2 (fds.as_mut_ptr (), libc::O_CLOEXEC | libc::O_NONBLOCK) } != 0 {
+		return Err (io::Error::last_os_error ());
+	}
+	Ok (WAKE_PIPE.get_or_init (|| (fds [0], fds [1])).0)
+}
+
+/// Make the terminal return [`InputEvent::Wake`] from any thread, so the editor redraws
+pub fn wake () {
+	let & (_, write_fd) = some_or! (WAKE_PIPE.get (), return);
+	unsafe { libc::write (write_fd, b"w".as_ptr () as * const libc::c_void, 1) };
+}
+
+/// Catch the signals which are turned into input events while the terminal is started
+fn install_signal_handlers () -> GenResult <()> {
+	install_signal_handler (libc::SIGWINCH, Some (handle_sigwinch)) ?;
+	for signal in [ libc::SIGHUP, libc::SIGINT, libc::SIGTERM ] {
+		install_signal_handler (signal, Some (handle_terminate)) ?;
+	}
+	Ok (())
+}
+
+/// Put back the default handling of the signals caught by [`install_signal_handlers`]
+fn reset_signal_handlers () -> GenResult <()> {
+	for signal in [ libc::SIGWINCH, libc::SIGHUP, libc::SIGINT, libc::SIGTERM ] {
+		install_signal_handler (signal, None) ?;
+	}
+	Ok (())
+}
+
+/// Install a signal handler without `SA_RESTART`, so that a blocking read is interrupted, or the default one
+fn install_signal_handler (signal: libc::c_int, handler: Option <extern "C" fn (libc::c_int)>) -> GenResult <()> {
+	unsafe {
+		let mut action: libc::sigaction = mem::zeroed ();
+		action.sa_sigaction = handler.map_or (libc::SIG_DFL, |handler| handler as libc::sighandler_t);
+		libc::sigemptyset (& mut action.sa_mask);
+		if libc::sigaction (signal, & action, ptr::null_mut ()) != 0 {
+			return Err (io::Error::last_os_error ().into ());
+		}
+	}
+	Ok (())
+}
+
+/// Interface between the editor and the terminal it draws on
+pub trait Backend: Write {
+
+	fn start (& mut self) -> GenResult <()>;
+
+	fn stop (& mut self) -> GenResult <()>;
+
+	/// Current size, if it can be read directly
+	fn size (& self) -> Option <(usize, usize)>;
+
+	/// Ask the terminal to report its size, for when it can't be read directly
+	fn request_size (& mut self) -> GenResult <()>;
+
+	/// Set the size of the screen model, the next flush will redraw everything
+	fn resize (& mut self, rows: usize, cols: usize);
+
+	/// Wait for the next input event, or `None` at the end of input
+	fn input (& mut self) -> GenResult <Option <InputEvent>>;
+
+	/// Copy text to the system clipboard
+	fn set_clipboard (& mut self, text: & str) -> GenResult <()>;
+
+	/// Ask for the contents of the system clipboard, which arrive later as an input event
+	fn request_clipboard (& mut self) -> GenResult <()>;
+
+	/// Set the window title, which is restored when stopping
+	fn set_title (& mut self, title: & str) -> GenResult <()>;
+
+	/// Set the shape of the cursor, which is reset when stopping
+	fn set_cursor_shape (& mut self, shape: CursorShape) -> GenResult <()>;
+
+	fn screen_mut (& mut self) -> & mut Screen;
+
+	fn move_to (& mut self, row: usize, col: usize) -> GenResult <()> {
+		self.screen_mut ().move_to (row, col);
+		Ok (())
+	}
+
+	fn clear_to_end (& mut self) -> GenResult <()> {
+		self.screen_mut ().clear_to_end ();
+		Ok (())
+	}
+
+	fn text_attr (& mut self, text_attr: TextAttr) -> GenResult <()> {
+		self.screen_mut ().set_attr (text_attr);
+		Ok (())
+	}
+
+}
 
 pub struct Terminal {
-	stdin: Stdin,
-	stdout: Stdout,
+	tty_in: FsFile,
+	tty_out: FsFile,
+	wake_fd: RawFd,
+	escape_delay: Duration,
+	sgr_cache: SgrCache,
 	termios: Option <Termios>,
 	buf_in: Vec <u8>,
 	buf_in_start: usize,
 	buf_in_end: usize,
 	buf_out: Vec <u8>,
+	screen: Screen,
+	prev_screen: Option <Screen>,
+	sync_output: bool,
+	clipboard_max_size: usize,
+	clipboard_pending: bool,
+	title: Option <String>,
+	cursor_shape: CursorShape,
 }
 
 impl Terminal {
-	pub fn new () -> GenResult <Terminal> {
+	pub fn new (config: & ConfigMisc) -> GenResult <Terminal> {
+		let wake_fd = open_wake_pipe () ?;
+		let (tty_in, tty_out) = Self::open_tty () ?;
+		let (rows, cols) = Self::query_size (& tty_out).unwrap_or ((25, 80));
 		Ok (Self {
-			stdin: io::stdin (),
-			stdout: io::stdout (),
+			tty_in,
+			tty_out,
+			wake_fd,
+			escape_delay: Duration::from_millis (config.escape_delay),
+			sgr_cache: SgrCache {
+				colour_depth: config.colour_depth.unwrap_or_else (ColourDepth::detect),
+				cache: HashMap::new (),
+			},
 			termios: None,
 			buf_in: vec! [0_u8; 1024],
 			buf_in_start: 0,
 			buf_in_end: 0,
 			buf_out: vec! [],
+			screen: Screen::new (rows, cols),
+			prev_screen: None,
+			sync_output: false,
+			clipboard_max_size: config.osc52_max_size,
+			clipboard_pending: false,
+			title: None,
+			cursor_shape: CursorShape::Default,
 		})
 	}
-	pub fn start (& mut self) -> GenResult <()> {
-		assert! (self.termios.is_none ());
-		let mut termios = Termios::from_fd (self.stdin.as_raw_fd ()) ?;
-		self.termios = Some (termios);
-		termios::cfmakeraw (& mut termios);
-		termios::tcsetattr (self.stdin.as_raw_fd (), termios::TCSANOW, & termios) ?;
-		write! (self.stdout, "\x1b[?1049h") ?;
-		self.stdout.flush () ?;
-		Ok (())
+	/// Use standard input and output if they are a terminal, otherwise open `/dev/tty`
+	fn open_tty () -> GenResult <(FsFile, FsFile)> {
+		if io::stdin ().is_terminal () && io::stdout ().is_terminal () {
+			Ok ((
+				io::stdin ().as_fd ().try_clone_to_owned () ?.into (),
+				io::stdout ().as_fd ().try_clone_to_owned () ?.into (),
+			))
+		} else {
+			let tty = OpenOptions::new ().read (true).write (true).open ("/dev/tty")
+				.map_err (|err| format! ("Can't open /dev/tty: {err}")) ?;
+			Ok ((tty.try_clone () ?, tty))
+		}
 	}
-	pub fn stop (& mut self) -> GenResult <()> {
-		assert! (self.termios.is_some ());
-		write! (self, "\x1b[?1049l").unwrap ();
-		self.flush ().unwrap ();
-		let termios = self.termios.take ().unwrap ();
-		termios::tcsetattr (self.stdin.as_raw_fd (), termios::TCSANOW, & termios).unwrap ();
-		self.stdout.flush () ?;
-		Ok (())
+	fn query_size (tty: & FsFile) -> Option <(usize, usize)> {
+		let mut size: libc::winsize = unsafe { mem::zeroed () };
+		if unsafe { libc::ioctl (tty.as_raw_fd (), libc::TIOCGWINSZ, & mut size) } != 0 {
+			return None;
+		}
+		(size.ws_row != 0 && size.ws_col != 0)
+			.then_some ((size.ws_row as usize, size.ws_col as usize))
 	}
-	pub fn input (& mut self) -> GenResult <InputEvent> {
+	fn input_event (& mut self) -> GenResult <InputEvent> {
+		// only the next input can be the reply, since many terminals ignore the query
+		let clipboard_pending = mem::take (& mut self.clipboard_pending);
 		match self.read () ? {
 			by @ b'\x20' ..= b'\x7e' =>
 				Ok (InputEvent::Key (Key::Char (by as char))),
+			b'\t' => Ok (InputEvent::Key (Key::Tab)),
+			b'\r' => Ok (InputEvent::Key (Key::Enter)),
 			by @ b'\x01' ..= b'\x1a' =>
 				Ok (InputEvent::CtrlKey (Key::Char ((by + b'a' - b'\x01') as char))),
-			b'\x1b' => match self.read () ? {
-				by @ b'\x01' ..= b'\x1a' =>
-					Ok (InputEvent::CtrlAltKey (Key::Char ((by + b'a' - b'\x01') as char))),
-				b'[' => {
-					let mut vals = [0; 3];
-					let mut vals_len = 0;
-					let mut val = None;
-					let err = |vals: [u32; 3], vals_len, by: u8|
-						format! ("Invalid CSI: {:?} 0x{by:02x}", & vals [ .. vals_len]);
-					loop {
-						match self.read () ? {
-							by @ b'0' ..= b'9' =>
-								val = Some (val.unwrap_or (0_u32) * 10 + (by - b'0') as u32),
-							b';' => {
-								if vals_len == 2 { return Err ("Invalid CSI".into ()) }
-								vals [vals_len] = val.ok_or ("Invalid CSI") ?;
-								vals_len += 1;
-								val = None;
-							},
-							by => {
-								if let Some (val) = val {
-									if vals_len == 3 { return Err ("Invalid CSI".into ()) }
-									vals [vals_len] = val;
-									vals_len += 1;
-								}
-								return Self::decode_csi (& vals [ .. vals_len], by)
-									.ok_or_else (|| err (vals, vals_len, by).into ());
-							},
+			b'\x1b' if ! self.poll (self.escape_delay) ? => Ok (InputEvent::Key (Key::Escape)),
+			b'\x1b' => self.input_escape (clipboard_pending),
+			b'\x7f' => Ok (InputEvent::Key (Key::Backspace)),
+			b'\x00' => Ok (InputEvent::CtrlKey (Key::Char (' '))),
+			by @ b'\x80' ..= b'\xff' => self.input_utf8 (by),
+			by => Err (format! ("Invalid input: {by:02x}").into ()),
+		}
+	}
+	fn input_escape (& mut self, clipboard_pending: bool) -> GenResult <InputEvent> {
+		match self.read () ? {
+			b']' if clipboard_pending => self.input_osc (),
+			b'\t' => Ok (InputEvent::AltKey (Key::Tab)),
+			b'\r' => Ok (InputEvent::AltKey (Key::Enter)),
+			by @ b'\x01' ..= b'\x1a' =>
+				Ok (InputEvent::CtrlAltKey (Key::Char ((by + b'a' - b'\x01') as char))),
+			b'[' => self.input_csi (),
+			by @ b'\x20' ..= b'\x7e' => Ok (InputEvent::AltKey (Key::Char (by as char))),
+			b'\x7f' => Ok (InputEvent::AltKey (Key::Backspace)),
+			by => Err (format! ("ESC + 0x{by:02x}").into ()),
+		}
+	}
+	fn input_csi (& mut self) -> GenResult <InputEvent> {
+		let mut vals = [0; 3];
+		let mut vals_len = 0;
+		let mut val = None;
+		let mut sub_param = false;
+		let mut intermediate = None;
+		let err = |prefix: Option <u8>, vals: [u32; 3], vals_len, by: u8|
+			format! ("Invalid CSI: {prefix}{:?} 0x{by:02x}", & vals [ .. vals_len],
+				prefix = prefix.map (|by| (by as char).to_string ()).unwrap_or_default ());
+		let mut by = self.read () ?;
+		let prefix = matches! (by, b'<' ..= b'?').then_some (by);
+		if prefix.is_some () { by = self.read () ? }
+		loop {
+			match by {
+				b'0' ..= b'9' if sub_param => (),
+				b'0' ..= b'9' =>
+					val = Some (val.unwrap_or (0_u32) * 10 + (by - b'0') as u32),
+				b':' => sub_param = true,
+				b'\x20' ..= b'\x2f' => intermediate = Some (by),
+				b';' => {
+					sub_param = false;
+					if vals_len == 2 { return Err ("Invalid CSI".into ()) }
+					vals [vals_len] = val.ok_or ("Invalid CSI") ?;
+					vals_len += 1;
+					val = None;
+				},
+				by => {
+					if let Some (val) = val {
+						if vals_len == 3 { return Err ("Invalid CSI".into ()) }
+						vals [vals_len] = val;
+						vals_len += 1;
+					}
+					if (prefix, & vals [ .. vals_len], by) == (None, & [200], b'~') {
+						return self.input_paste ();
+					}
+					if (prefix, intermediate, by) == (Some (b'?'), None, b'u') {
+						// the terminal supports the kitty keyboard protocol, so turn it on
+						self.write_raw ("\x1b[>1u");
+						self.flush_raw () ?;
+						KEYBOARD_PUSHED.store (true, Ordering::Relaxed);
+						return self.input_event ();
+					}
+					if (prefix, intermediate, by) == (Some (b'?'), Some (b'$'), b'y') {
+						if let & [2026, mode] = & vals [ .. vals_len] {
+							self.sync_output = mode == 1 || mode == 2;
 						}
+						return self.input_event ();
 					}
+					let event = match prefix {
+						None => Self::decode_csi (& vals [ .. vals_len], by),
+						Some (b'<') => Self::decode_mouse (& vals [ .. vals_len], by),
+						Some (_) => None,
+					};
+					return event.ok_or_else (|| err (prefix, vals, vals_len, by).into ());
 				},
-				by @ b'\x20' ..= b'\x7e' => Ok (InputEvent::AltKey (Key::Char (by as char))),
-				by => Err (format! ("ESC + 0x{by:02x}").into ()),
-			},
-			b'\x7f' => Ok (InputEvent::Key (Key::Backspace)),
-			by_0 @ b'\xc0' ..= b'\xdf' => {
-				let err = |by_0, by_1| format! ("Invalid UTF-8: {by_0:02x} {by_1:02x}");
-				match self.read () ? {
-					by_1 @ b'\x80' ..= b'\xbf' => Ok (InputEvent::Key (Key::Char (char::from_u32 (
-						((by_0 as u32 & 0x1f) << 6)
-							| (by_1 as u32 & 0x3f)
-					).ok_or_else (|| err (by_0, by_1)) ?))),
-					by_1 => Err (err (by_0, by_1).into ()),
-				}
-			},
-			by_0 @ b'\xe0' ..= b'\xef' => {
-				let err = |by_0, by_1, by_2| format! ("Invalid UTF-8: {by_0:02x} {by_1:02x} {by_2:02x}");
-				match (self.read () ?, self.read () ?) {
-					(by_1 @ b'\x80' ..= b'\xbf', by_2 @ b'\x80' ..= b'\xbf') =>
-						Ok (InputEvent::Key (Key::Char (char::from_u32 (
-							((by_0 as u32 & 0x1f) << 12)
-								| ((by_1 as u32 & 0x3f) << 6)
-								| (by_2 as u32 & 0x3f)
-						).ok_or_else (|| err (by_0, by_1, by_2)) ?))),
-					(by_1, by_2) => Err (err (by_0, by_1, by_2).into ()),
-				}
-			},
-			by => Err (format! ("Invalid input: {by:02x}").into ()),
+			}
+			by = self.read () ?;
+		}
+	}
+	fn input_osc (& mut self) -> GenResult <InputEvent> {
+		let max_len = self.clipboard_max_size / 3 * 4 + 16;
+		let mut bytes = Vec::new ();
+		let mut too_long = false;
+		loop {
+			match self.read () ? {
+				b'\x07' => break,
+				b'\x1b' => {
+					if self.read () ? == b'\\' { break }
+					// an escape which doesn't end the string starts a new sequence
+					self.unread ();
+					return self.input_escape (false);
+				},
+				_ if max_len <= bytes.len () => too_long = true,
+				by => bytes.push (by),
+			}
 		}
+		let bytes = some_or! (bytes.strip_prefix (b"52;"), return Err ("Invalid OSC".into ()));
+		if too_long { return Err ("Clipboard contents too large".into ()) }
+		let data = bytes.iter ().position (|& by| by == b';')
+			.and_then (|pos| base64_decode (& String::from_utf8_lossy (& bytes [pos + 1 .. ])))
+			.ok_or ("Invalid clipboard data") ?;
+		Ok (InputEvent::Clipboard (String::from_utf8_lossy (& data).into_owned ()))
+	}
+	fn input_utf8 (& mut self, mut by: u8) -> GenResult <InputEvent> {
+		let mut decoder = Utf8Decoder::new ();
+		loop {
+			match decoder.push (by) {
+				Utf8Step::Char (ch) => return Ok (InputEvent::Key (Key::Char (ch))),
+				Utf8Step::Pending => by = self.read () ?,
+				Utf8Step::Invalid (bytes) =>
+					return Err (format! ("Invalid UTF-8: {}", fmt_bytes (& bytes)).into ()),
+				Utf8Step::Resync (bytes) => {
+					self.unread ();
+					return Err (format! ("Invalid UTF-8: {}", fmt_bytes (& bytes)).into ());
+				},
+			}
+		}
+	}
+	fn input_paste (& mut self) -> GenResult <InputEvent> {
+		const END: & [u8] = b"\x1b[201~";
+		let mut bytes = Vec::new ();
+		while ! bytes.ends_with (END) {
+			bytes.push (self.read () ?);
+		}
+		bytes.truncate (bytes.len () - END.len ());
+		let text = String::from_utf8_lossy (& bytes)
+			.replace ("\r\n", "\n")
+			.replace ('\r', "\n");
+		Ok (InputEvent::Paste (text))
+	}
+	/// Wait up to `timeout` for more input to become available
+	fn poll (& self, timeout: Duration) -> io::Result <bool> {
+		if self.buf_in_start < self.buf_in_end { return Ok (true) }
+		let mut poll_fd = libc::pollfd {
+			fd: self.tty_in.as_raw_fd (),
+			events: libc::POLLIN,
+			revents: 0,
+		};
+		loop {
+			let timeout = timeout.as_millis ().try_into ().unwrap_or (libc::c_int::MAX);
+			match unsafe { libc::poll (& mut poll_fd, 1, timeout) } {
+				0 => return Ok (false),
+				1 .. => return Ok (true),
+				_ => {
+					let err = io::Error::last_os_error ();
+					if err.kind () != io::ErrorKind::Interrupted { return Err (err) }
+				},
+			}
+		}
+	}
+	/// Wait until there is input, returning false instead if woken by [`wake`]
+	fn wait (& self) -> io::Result <bool> {
+		let mut poll_fds = [
+			libc::pollfd { fd: self.tty_in.as_raw_fd (), events: libc::POLLIN, revents: 0 },
+			libc::pollfd { fd: self.wake_fd, events: libc::POLLIN, revents: 0 },
+		];
+		if unsafe { libc::poll (poll_fds.as_mut_ptr (), 2, -1) } < 0 {
+			return Err (io::Error::last_os_error ());
+		}
+		if poll_fds [0].revents != 0 { return Ok (true) }
+		let mut buf = [0_u8; 64];
+		while 0 < unsafe { libc::read (self.wake_fd, buf.as_mut_ptr () as * mut libc::c_void, buf.len ()) } {}
+		Ok (false)
+	}
+	fn fill (& mut self) -> io::Result <()> {
+		self.buf_in_start = 0;
+		self.buf_in_end = 0;
+		self.buf_in_end = self.tty_in.read (self.buf_in.as_mut_slice ()) ?;
+		if self.buf_in_end == 0 { return Err (io::ErrorKind::UnexpectedEof.into ()) }
+		Ok (())
 	}
 	fn read (& mut self) -> GenResult <u8> {
-		if self.buf_in_start == self.buf_in_end {
-			self.buf_in_start = 0;
-			self.buf_in_end = self.stdin.read (self.buf_in.as_mut_slice ()) ?;
+		while self.buf_in_start == self.buf_in_end {
+			match self.fill () {
+				Ok (()) => (),
+				Err (err) if err.kind () == io::ErrorKind::Interrupted => (),
+				Err (err) => return Err (err.into ()),
+			}
 		}
 		let byte = self.buf_in [self.buf_in_start];
 		self.buf_in_start += 1;
 		Ok (byte)
 	}
-	pub fn move_to (& mut self, row: usize, col: usize) -> GenResult <()> {
-		write! (self, "\x1b[{row};{col}H", row = row + 1, col = col + 1) ?;
-		Ok (())
+	fn unread (& mut self) {
+		debug_assert! (0 < self.buf_in_start);
+		self.buf_in_start -= 1;
 	}
-	pub fn clear_to_end (& mut self) -> GenResult <()> {
-		write! (self, "\x1b[K") ?;
-		Ok (())
+	fn write_raw (& mut self, src: & str) {
+		self.buf_out.extend_from_slice (src.as_bytes ());
 	}
-	pub fn text_attr (& mut self, text_attr: TextAttr) -> GenResult <()> {
-		write! (self, "\x1b[0m") ?;
-		if text_attr.bold {
-			write! (self, "\x1b[1m") ?;
+	fn flush_raw (& mut self) -> io::Result <()> {
+		let mut buf: & [u8] = & self.buf_out;
+		while ! buf.is_empty () {
+			let num_bytes = self.tty_out.write (buf) ?;
+			buf = & buf [num_bytes .. ];
 		}
-		if text_attr.italic {
-			write! (self, "\x1b[3m") ?;
+		self.buf_out.clear ();
+		self.tty_out.flush () ?;
+		Ok (())
+	}
+	/// Emit the changes between the previous frame and the screen model
+	fn render (& mut self) {
+		let out = & mut self.buf_out;
+		if self.sync_output { out.extend_from_slice (b"\x1b[?2026h") }
+		out.extend_from_slice (b"\x1b[?25l");
+		let prev = self.prev_screen.as_ref ()
+			.filter (|prev| (prev.rows (), prev.cols ()) == (self.screen.rows (), self.screen.cols ()));
+		Self::render_cells (out, & self.screen, prev, & mut self.sgr_cache);
+		let (row, col) = self.screen.cursor ();
+		write! (out, "\x1b[{row};{col}H", row = row + 1, col = col + 1).unwrap ();
+		out.extend_from_slice (b"\x1b[?25h");
+		if self.sync_output { out.extend_from_slice (b"\x1b[?2026l") }
+		self.prev_screen = Some (self.screen.clone ());
+	}
+	/// Emit the cells which differ from the previous frame, or all of them if there isn't one
+	fn render_cells (out: & mut Vec <u8>, screen: & Screen, prev: Option <& Screen>, sgr_cache: & mut SgrCache) {
+		let mut pos = None;
+		let mut attr = None;
+		for row in 0 .. screen.rows () {
+			let cells = screen.row (row);
+			let prev_cells = prev.map (|prev| prev.row (row));
+			let tail_attr = some_or! (cells.last (), continue).attr;
+			let tail_start = cells.iter ()
+				.rposition (|cell| ! cell.is_blank () || cell.attr != tail_attr)
+				.map_or (0, |col| col + 1);
+			for (col, cell) in cells.iter ().enumerate () {
+				if cell.ch == '\0' { continue }
+				if prev_cells.is_some_and (|prev_cells| prev_cells [col] == * cell) { continue }
+				match pos {
+					Some ((pos_row, pos_col)) if (pos_row, pos_col) == (row, col) => (),
+					Some ((pos_row, pos_col)) if pos_row == row && pos_col < col && col - pos_col <= 4
+							&& cells [pos_col .. col].iter ().all (|cell| Some (cell.attr) == attr) =>
+						for cell in & cells [pos_col .. col] {
+							write! (out, "{cell}").unwrap ();
+						},
+					_ => write! (out, "\x1b[{row};{col}H", row = row + 1, col = col + 1).unwrap (),
+				}
+				if attr != Some (cell.attr) {
+					out.extend_from_slice (sgr_cache.get (cell.attr).as_bytes ());
+					attr = Some (cell.attr);
+				}
+				if tail_start <= col {
+					out.extend_from_slice (b"\x1b[K");
+					pos = Some ((row, col));
+					break;
+				}
+				write! (out, "{cell}").unwrap ();
+				pos = Some ((row, col + if cell.wide { 2 } else { 1 }));
+			}
 		}
-		if text_attr.underline {
-			write! (self, "\x1b[4m") ?;
+	}
+	fn decode_mouse (vals: & [u32], by: u8) -> Option <InputEvent> {
+		let & [flags, col, row] = vals else { return None };
+		let (row, col) = (row.checked_sub (1) ?, col.checked_sub (1) ?);
+		if flags & 64 != 0 {
+			return match flags & 3 {
+				0 => Some (InputEvent::MouseWheel { delta: -1, row, col }),
+				1 => Some (InputEvent::MouseWheel { delta: 1, row, col }),
+				_ => None,
+			};
 		}
-		write! (self,
-			"\x1b[48;2;{red};{green};{blue}m",
-			red = text_attr.bg.red,
-			green = text_attr.bg.green,
-			blue = text_attr.bg.blue) ?;
-		write! (self,
-			"\x1b[38;2;{red};{green};{blue}m",
-			red = text_attr.fg.red,
-			green = text_attr.fg.green,
-			blue = text_attr.fg.blue) ?;
-		Ok (())
+		let button = match flags & 3 {
+			0 => MouseButton::Left,
+			1 => MouseButton::Middle,
+			2 => MouseButton::Right,
+			_ => return None,
+		};
+		Some (match (flags & 32 != 0, by) {
+			(true, _) => InputEvent::MouseDrag { button, row, col },
+			(false, b'M') => InputEvent::MousePress { button, row, col },
+			(false, b'm') => InputEvent::MouseRelease { button, row, col },
+			_ => return None,
+		})
+	}
+	fn decode_csi_u (code: u32, mods: u32) -> Option <InputEvent> {
+		let key = match code {
+			9 => Key::Tab,
+			13 => Key::Enter,
+			27 => Key::Escape,
+			127 => Key::Backspace,
+			0xe000 ..= 0xf8ff => return None,
+			_ => Key::Char (char::from_u32 (code) ?),
+		};
+		Some (match mods.checked_sub (1) ? & ! 0xc0 {
+			0 => InputEvent::Key (key),
+			1 => InputEvent::ShiftKey (key),
+			2 => InputEvent::AltKey (key),
+			3 => InputEvent::AltShiftKey (key),
+			4 => InputEvent::CtrlKey (key),
+			5 => InputEvent::CtrlShiftKey (key),
+			6 => InputEvent::CtrlAltKey (key),
+			_ => return None,
+		})
 	}
 	fn decode_csi (vals: & [u32], by: u8) -> Option <InputEvent> {
 		Some (match (
@@ -160,7 +547,6 @@ impl Terminal {
 			by,
 		) {
 			(& [8, rows, cols], b't') => InputEvent::TextSize { rows, cols },
-			(& [9, rows, cols], b't') => InputEvent::ScreenSize { rows, cols },
 			(& [], b'A') => InputEvent::Key (Key::Up),
 			(& [1, 2], b'A') => InputEvent::ShiftKey (Key::Up),
 			(& [1, 3], b'A') => InputEvent::AltKey (Key::Up),
@@ -186,7 +572,11 @@ impl Terminal {
 			(& [1, 5], b'D') => InputEvent::CtrlKey (Key::Left),
 			(& [1, 6], b'D') => InputEvent::CtrlShiftKey (Key::Left),
 			(& [], b'F') => InputEvent::Key (Key::End),
+			(& [1, 2], b'F') => InputEvent::ShiftKey (Key::End),
 			(& [], b'H') => InputEvent::Key (Key::Home),
+			(& [1, 2], b'H') => InputEvent::ShiftKey (Key::Home),
+			(& [], b'I') => InputEvent::FocusIn,
+			(& [], b'O') => InputEvent::FocusOut,
 			(& [1, 2], b'P') => InputEvent::ShiftKey (Key::F1),
 			(& [1, 5], b'P') => InputEvent::CtrlKey (Key::F1),
 			(& [1, 6], b'P') => InputEvent::CtrlShiftKey (Key::F1),
@@ -208,7 +598,9 @@ impl Terminal {
 			(& [3, 5], b'~') => InputEvent::CtrlKey (Key::Delete),
 			(& [3, 6], b'~') => InputEvent::CtrlShiftKey (Key::Delete),
 			(& [5], b'~') => InputEvent::Key (Key::PageUp),
+			(& [5, 2], b'~') => InputEvent::ShiftKey (Key::PageUp),
 			(& [6], b'~') => InputEvent::Key (Key::PageDown),
+			(& [6, 2], b'~') => InputEvent::ShiftKey (Key::PageDown),
 			(& [15], b'~') => InputEvent::Key (Key::F5),
 			(& [15, 2, 2], b'~') => InputEvent::ShiftKey (Key::F5),
 			(& [15, 5], b'~') => InputEvent::CtrlKey (Key::F5),
@@ -237,15 +629,127 @@ impl Terminal {
 			(& [24], b'~') => InputEvent::Key (Key::F12),
 			(& [24, 2], b'~') => InputEvent::ShiftKey (Key::F12),
 			(& [24, 6], b'~') => InputEvent::CtrlShiftKey (Key::F12),
+			(& [code], b'u') => return Self::decode_csi_u (code, 1),
+			(& [code, mods], b'u') => return Self::decode_csi_u (code, mods),
 			_ => return None,
 		})
 	}
 }
 
+impl Backend for Terminal {
+
+	fn start (& mut self) -> GenResult <()> {
+		assert! (self.termios.is_none ());
+		install_signal_handlers () ?;
+		let mut termios = Termios::from_fd (self.tty_in.as_raw_fd ()) ?;
+		self.termios = Some (termios);
+		* STARTED.lock ().unwrap () = Some ((self.tty_in.as_raw_fd (), self.tty_out.as_raw_fd (), termios));
+		termios::cfmakeraw (& mut termios);
+		termios::tcsetattr (self.tty_in.as_raw_fd (), termios::TCSANOW, & termios) ?;
+		write! (self.tty_out, "{START_SEQUENCE}") ?;
+		self.tty_out.flush () ?;
+		self.prev_screen = None;
+		self.title = None;
+		self.cursor_shape = CursorShape::Default;
+		Ok (())
+	}
+
+	fn stop (& mut self) -> GenResult <()> {
+		assert! (self.termios.is_some ());
+		self.termios = None;
+		let result = self.flush_raw ();
+		restore_terminal ();
+		result ?;
+		reset_signal_handlers ()
+	}
+
+	fn size (& self) -> Option <(usize, usize)> {
+		Self::query_size (& self.tty_out)
+	}
+
+	fn request_size (& mut self) -> GenResult <()> {
+		self.write_raw ("\x1b[18t");
+		self.flush_raw () ?;
+		Ok (())
+	}
+
+	fn resize (& mut self, rows: usize, cols: usize) {
+		self.screen = Screen::new (rows, cols);
+		self.prev_screen = None;
+	}
+
+	fn input (& mut self) -> GenResult <Option <InputEvent>> {
+		while self.buf_in_start == self.buf_in_end {
+			let signal = TERMINATED.swap (0, Ordering::Relaxed);
+			if signal != 0 {
+				return Ok (Some (InputEvent::Terminate { signal }));
+			}
+			// clear the flag before reading the size, so a resize which lands in between is
+			// picked up next time round rather than lost
+			if RESIZED.swap (false, Ordering::Relaxed) {
+				match self.size () {
+					Some ((rows, cols)) =>
+						return Ok (Some (InputEvent::TextSize { rows: rows as u32, cols: cols as u32 })),
+					None => self.request_size () ?,
+				}
+			}
+			match self.wait () {
+				Ok (true) => (),
+				Ok (false) => return Ok (Some (InputEvent::Wake)),
+				Err (err) if err.kind () == io::ErrorKind::Interrupted => continue,
+				Err (err) => return Err (err.into ()),
+			}
+			match self.fill () {
+				Ok (()) => (),
+				Err (err) if err.kind () == io::ErrorKind::Interrupted => (),
+				// a hangup closes the terminal, so report the signal rather than end of input
+				Err (err) if err.kind () == io::ErrorKind::UnexpectedEof =>
+					if TERMINATED.load (Ordering::Relaxed) == 0 { return Ok (None) },
+				Err (err) => return Err (err.into ()),
+			}
+		}
+		self.input_event ().map (Some)
+	}
+
+	fn set_clipboard (& mut self, text: & str) -> GenResult <()> {
+		if self.clipboard_max_size < text.len () { return Err ("Too large for clipboard".into ()) }
+		self.write_raw (& format! ("\x1b]52;c;{data}\x07", data = base64_encode (text.as_bytes ())));
+		self.flush_raw () ?;
+		Ok (())
+	}
+
+	fn request_clipboard (& mut self) -> GenResult <()> {
+		self.clipboard_pending = true;
+		self.write_raw ("\x1b]52;c;?\x07");
+		self.flush_raw () ?;
+		Ok (())
+	}
+
+	fn set_title (& mut self, title: & str) -> GenResult <()> {
+		let title: String = title.chars ().filter (|ch| ! ch.is_control ()).collect ();
+		if self.title.as_ref () == Some (& title) { return Ok (()) }
+		self.write_raw (& format! ("\x1b]2;{title}\x07"));
+		self.title = Some (title);
+		Ok (())
+	}
+
+	fn set_cursor_shape (& mut self, shape: CursorShape) -> GenResult <()> {
+		if self.cursor_shape == shape { return Ok (()) }
+		self.write_raw (& format! ("\x1b[{param} q", param = shape.decscusr ()));
+		self.cursor_shape = shape;
+		Ok (())
+	}
+
+	fn screen_mut (& mut self) -> & mut Screen {
+		& mut self.screen
+	}
+
+}
+
 impl Drop for Terminal {
 	fn drop (& mut self) {
 		if self.termios.is_some () {
-			self.stop ().unwrap ();
+			self.stop ().ok ();
 		}
 	}
 }
@@ -253,24 +757,240 @@ impl Drop for Terminal {
 impl Write for Terminal {
 
 	fn write (& mut self, bytes: & [u8]) -> io::Result <usize> {
-		self.buf_out.extend_from_slice (bytes);
+		self.screen.put_str (& String::from_utf8_lossy (bytes));
 		Ok (bytes.len ())
 	}
 
 	fn flush (& mut self) -> io::Result <()> {
-		let mut buf: & [u8] = & self.buf_out;
-		while ! buf.is_empty () {
-			let num_bytes = self.stdout.write (buf) ?;
-			buf = & buf [num_bytes .. ];
+		self.render ();
+		self.flush_raw ()
+	}
+
+}
+
+/// Cache of escape sequences for each text attribute, at the terminal's colour depth
+struct SgrCache {
+	colour_depth: ColourDepth,
+	cache: HashMap <TextAttr, String>,
+}
+
+impl SgrCache {
+	fn get (& mut self, text_attr: TextAttr) -> & str {
+		let colour_depth = self.colour_depth;
+		self.cache.entry (text_attr)
+			.or_insert_with (|| text_attr.sgr (colour_depth))
+	}
+}
+
+/// Model of the terminal's contents, as a grid of cells, plus the drawing position and attribute
+#[ derive (Clone, Debug) ]
+pub struct Screen {
+	rows: usize,
+	cols: usize,
+	cells: Vec <Cell>,
+	cursor: (usize, usize),
+	attr: TextAttr,
+}
+
+/// One cell of the screen model, where the cell after a double-width cluster holds `'\0'`
+#[ derive (Clone, Debug, Eq, PartialEq) ]
+pub struct Cell {
+	pub ch: char,
+	pub tail: Option <Rc <str>>,
+	pub wide: bool,
+	pub attr: TextAttr,
+}
+
+impl Cell {
+
+	fn blank (attr: TextAttr) -> Self {
+		Self { ch: ' ', tail: None, wide: false, attr }
+	}
+
+	fn is_blank (& self) -> bool {
+		self.ch == ' ' && self.tail.is_none ()
+	}
+
+}
+
+impl fmt::Display for Cell {
+	fn fmt (& self, formatter: & mut fmt::Formatter) -> fmt::Result {
+		if self.ch == '\0' { return Ok (()) }
+		write! (formatter, "{ch}{tail}", ch = self.ch, tail = self.tail.as_deref ().unwrap_or_default ())
+	}
+}
+
+impl Screen {
+
+	pub fn new (rows: usize, cols: usize) -> Self {
+		let cell = Cell::blank (TextAttr::default ());
+		Self {
+			rows,
+			cols,
+			cells: vec! [cell; rows * cols],
+			cursor: (0, 0),
+			attr: TextAttr::default (),
+		}
+	}
+
+	pub fn rows (& self) -> usize {
+		self.rows
+	}
+
+	pub fn cols (& self) -> usize {
+		self.cols
+	}
+
+	pub fn cursor (& self) -> (usize, usize) {
+		self.cursor
+	}
+
+	pub fn row (& self, row: usize) -> & [Cell] {
+		& self.cells [row * self.cols .. (row + 1) * self.cols]
+	}
+
+	pub fn move_to (& mut self, row: usize, col: usize) {
+		self.cursor = (row, col);
+	}
+
+	pub fn set_attr (& mut self, attr: TextAttr) {
+		self.attr = attr;
+	}
+
+	pub fn put_str (& mut self, src: & str) {
+		for cluster in src.graphemes (true) {
+			if let Some (name) = control_name (cluster) {
+				// never pass a control character through to the terminal
+				self.put_str (& name);
+				continue;
+			}
+			let (row, col) = self.cursor;
+			let width = cluster_width (cluster);
+			let mut chars = cluster.chars ();
+			let first = chars.next ().unwrap ();
+			let (ch, tail) = if first.width () == Some (0) {
+				('\u{25cc}', Some (cluster.into ()))
+			} else {
+				(first, (! chars.as_str ().is_empty ()).then (|| chars.as_str ().into ()))
+			};
+			if col + width <= self.cols {
+				self.put (row, col, Cell { ch, tail, wide: width == 2, attr: self.attr });
+			} else {
+				self.put (row, col, Cell::blank (self.attr));
+			}
+			self.cursor.1 += width;
+		}
+	}
+
+	pub fn clear_to_end (& mut self) {
+		let (row, col) = self.cursor;
+		for col in col .. self.cols {
+			self.put (row, col, Cell::blank (self.attr));
+		}
+	}
+
+	fn put (& mut self, row: usize, col: usize, cell: Cell) {
+		let width = if cell.wide { 2 } else { 1 };
+		if self.rows <= row || self.cols < col + width { return }
+		let idx = row * self.cols + col;
+		for idx in idx .. idx + width {
+			self.split_wide (idx);
+		}
+		if cell.wide {
+			self.cells [idx + 1] = Cell { ch: '\0', tail: None, wide: false, attr: cell.attr };
+		}
+		self.cells [idx] = cell;
+	}
+
+	/// Blank out both halves of a double-width cluster, when one of them is about to be replaced
+	fn split_wide (& mut self, idx: usize) {
+		if self.cells [idx].ch == '\0' {
+			self.cells [idx - 1] = Cell::blank (self.cells [idx - 1].attr);
+			self.cells [idx] = Cell::blank (self.cells [idx].attr);
+		} else if self.cells [idx].wide {
+			self.cells [idx] = Cell::blank (self.cells [idx].attr);
+			self.cells [idx + 1] = Cell::blank (self.cells [idx + 1].attr);
 		}
-		self.buf_out.clear ();
-		self.stdout.flush () ?;
-		Ok (())
 	}
 
 }
 
-#[ derive (Clone, Copy, Debug, Eq, PartialEq) ]
+/// Incremental UTF-8 decoder, fed one byte at a time
+#[ derive (Clone, Debug, Default) ]
+pub struct Utf8Decoder {
+	buf: [u8; 4],
+	len: usize,
+	want: usize,
+}
+
+#[ derive (Clone, Debug, Eq, PartialEq) ]
+pub enum Utf8Step {
+
+	/// A complete character was decoded
+	Char (char),
+
+	/// More bytes are needed to complete the character
+	Pending,
+
+	/// The bytes, including the one just pushed, are not valid UTF-8
+	Invalid (Vec <u8>),
+
+	/// The bytes before the one just pushed are not valid UTF-8, the new byte was not consumed
+	Resync (Vec <u8>),
+
+}
+
+impl Utf8Decoder {
+
+	pub fn new () -> Self {
+		Self::default ()
+	}
+
+	pub fn push (& mut self, by: u8) -> Utf8Step {
+		if self.len == 0 {
+			self.want = match by {
+				b'\x00' ..= b'\x7f' => return Utf8Step::Char (by as char),
+				b'\xc2' ..= b'\xdf' => 2,
+				b'\xe0' ..= b'\xef' => 3,
+				b'\xf0' ..= b'\xf4' => 4,
+				_ => return Utf8Step::Invalid (vec! [ by ]),
+			};
+			self.buf [0] = by;
+			self.len = 1;
+			return Utf8Step::Pending;
+		}
+		let (min, max) = match (self.len, self.buf [0]) {
+			(1, b'\xe0') => (b'\xa0', b'\xbf'),
+			(1, b'\xed') => (b'\x80', b'\x9f'),
+			(1, b'\xf0') => (b'\x90', b'\xbf'),
+			(1, b'\xf4') => (b'\x80', b'\x8f'),
+			_ => (b'\x80', b'\xbf'),
+		};
+		if by < min || max < by {
+			let bytes = self.buf [ .. self.len].to_vec ();
+			self.len = 0;
+			return Utf8Step::Resync (bytes);
+		}
+		self.buf [self.len] = by;
+		self.len += 1;
+		if self.len < self.want { return Utf8Step::Pending }
+		let lead_mask = match self.want { 2 => 0x1f, 3 => 0x0f, _ => 0x07 };
+		let val = self.buf [1 .. self.len].iter ()
+			.fold (self.buf [0] as u32 & lead_mask, |val, & by| (val << 6) | (by as u32 & 0x3f));
+		self.len = 0;
+		Utf8Step::Char (char::from_u32 (val).unwrap ())
+	}
+
+}
+
+fn fmt_bytes (bytes: & [u8]) -> String {
+	bytes.iter ()
+		.map (|by| format! ("{by:02x}"))
+		.collect::<Vec <_>> ()
+		.join (" ")
+}
+
+#[ derive (Clone, Debug, Eq, PartialEq) ]
 pub enum InputEvent {
 	Key (Key),
 	ShiftKey (Key),
@@ -280,18 +1000,32 @@ pub enum InputEvent {
 	AltKey (Key),
 	AltShiftKey (Key),
 	TextSize { rows: u32, cols: u32 },
-	ScreenSize { rows: u32, cols: u32 },
+	Paste (String),
+	Clipboard (String),
+	FocusIn,
+	FocusOut,
+	MousePress { button: MouseButton, row: u32, col: u32 },
+	MouseRelease { button: MouseButton, row: u32, col: u32 },
+	MouseDrag { button: MouseButton, row: u32, col: u32 },
+	MouseWheel { delta: i32, row: u32, col: u32 },
+	Terminate { signal: i32 },
+	Wake,
+}
+
+#[ derive (Clone, Copy, Debug, Eq, PartialEq) ]
+pub enum MouseButton {
+	Left, Middle, Right,
 }
 
 #[ derive (Clone, Copy, Debug, Eq, PartialEq) ]
 pub enum Key {
 	Char (char),
-	Tab, Insert, Delete, Backspace,
+	Enter, Escape, Tab, Insert, Delete, Backspace,
 	Up, Down, Left, Right, PageUp, PageDown, Home, End,
 	F1, F2, F3, F4, F5, F6, F7, F8, F9, F10, F11, F12,
 }
 
-#[ derive (Clone, Copy) ]
+#[ derive (Clone, Copy, Debug, Eq, Hash, PartialEq) ]
 pub struct TextAttr {
 	pub fg: Colour,
 	pub bg: Colour,
@@ -300,7 +1034,43 @@ pub struct TextAttr {
 	pub italic: bool,
 }
 
+impl Default for TextAttr {
+	fn default () -> Self {
+		Self {
+			fg: Colour::new (255, 255, 255),
+			bg: Colour::new (0, 0, 0),
+			bold: false,
+			underline: false,
+			italic: false,
+		}
+	}
+}
+
 impl TextAttr {
+
+	/// Escape sequence to select this attribute, with colours reduced to the given depth
+	fn sgr (& self, colour_depth: ColourDepth) -> String {
+		let mut sgr = "\x1b[0".to_owned ();
+		if self.bold { sgr.push_str (";1") }
+		if self.italic { sgr.push_str (";3") }
+		if self.underline { sgr.push_str (";4") }
+		for (base, colour) in [ (48, self.bg), (38, self.fg) ] {
+			match colour_depth {
+				ColourDepth::TrueColour => write! (sgr, ";{base};2;{red};{green};{blue}",
+					red = colour.red, green = colour.green, blue = colour.blue).unwrap (),
+				ColourDepth::Ansi256 =>
+					write! (sgr, ";{base};5;{idx}", idx = colour.to_ansi_256 ()).unwrap (),
+				ColourDepth::Ansi16 => {
+					let idx = colour.to_ansi_16 ();
+					let code = if idx < 8 { base - 8 + idx } else { base + 52 + idx - 8 };
+					write! (sgr, ";{code}").unwrap ();
+				},
+			}
+		}
+		sgr.push ('m');
+		sgr
+	}
+
 	pub fn build (palette: & HashMap <Rc <str>, Colour>, src: & ConfigTextAttr) -> GenResult <Self> {
 		Ok (Self {
 			fg: * palette.get (& src.fg).ok_or_else (|| format! ("Missing colour: {}", src.fg)) ?,
@@ -311,3 +1081,120 @@ impl TextAttr {
 		})
 	}
 }
+
+#[ cfg (test) ]
+mod tests {
+
+	use super::*;
+
+	#[ derive (Debug, Eq, PartialEq) ]
+	enum Out { Ch (char), Bad (& 'static [u8]) }
+
+	fn decode_all (bytes: & [u8]) -> Vec <Out> {
+		let mut decoder = Utf8Decoder::new ();
+		let mut result = Vec::new ();
+		let mut bytes = bytes.iter ().copied ().peekable ();
+		while let Some (& by) = bytes.peek () {
+			match decoder.push (by) {
+				Utf8Step::Char (ch) => { result.push (Out::Ch (ch)); bytes.next (); },
+				Utf8Step::Pending => { bytes.next (); },
+				Utf8Step::Invalid (bad) => { result.push (Out::Bad (bad.leak ())); bytes.next (); },
+				Utf8Step::Resync (bad) => result.push (Out::Bad (bad.leak ())),
+			}
+		}
+		result
+	}
+
+	#[ test ]
+	fn utf8_decoder () {
+		use Out::*;
+		let cases: & [(& str, & [u8], & [Out])] = & [
+			("ascii", b"az", & [ Ch ('a'), Ch ('z') ]),
+			("nul", b"\x00", & [ Ch ('\0') ]),
+			("two byte min", b"\xc2\x80", & [ Ch ('\u{80}') ]),
+			("two byte", b"\xc3\xa9", & [ Ch ('é') ]),
+			("two byte max", b"\xdf\xbf", & [ Ch ('\u{7ff}') ]),
+			("three byte min", b"\xe0\xa0\x80", & [ Ch ('\u{800}') ]),
+			("three byte", b"\xe2\x82\xac", & [ Ch ('€') ]),
+			("three byte high lead", b"\xef\xbf\xbd", & [ Ch ('\u{fffd}') ]),
+			("before surrogates", b"\xed\x9f\xbf", & [ Ch ('\u{d7ff}') ]),
+			("four byte min", b"\xf0\x90\x80\x80", & [ Ch ('\u{10000}') ]),
+			("four byte emoji", b"\xf0\x9f\x98\x80", & [ Ch ('😀') ]),
+			("four byte max", b"\xf4\x8f\xbf\xbf", & [ Ch ('\u{10ffff}') ]),
+			("mixed", b"a\xc3\xa9\xf0\x9f\x98\x80b", & [ Ch ('a'), Ch ('é'), Ch ('😀'), Ch ('b') ]),
+			("lone continuation", b"\x80a", & [ Bad (b"\x80"), Ch ('a') ]),
+			("overlong lead c0", b"\xc0\xafa", & [ Bad (b"\xc0"), Bad (b"\xaf"), Ch ('a') ]),
+			("overlong three byte", b"\xe0\x80\x80", & [ Bad (b"\xe0"), Bad (b"\x80"), Bad (b"\x80") ]),
+			("overlong four byte", b"\xf0\x8f\xbf\xbf", & [ Bad (b"\xf0"), Bad (b"\x8f"), Bad (b"\xbf"), Bad (b"\xbf") ]),
+			("surrogate", b"\xed\xa0\x80", & [ Bad (b"\xed"), Bad (b"\xa0"), Bad (b"\x80") ]),
+			("above max", b"\xf4\x90\x80\x80", & [ Bad (b"\xf4"), Bad (b"\x90"), Bad (b"\x80"), Bad (b"\x80") ]),
+			("invalid lead f5", b"\xf5a", & [ Bad (b"\xf5"), Ch ('a') ]),
+			("invalid lead ff", b"\xff", & [ Bad (b"\xff") ]),
+			("truncated two byte", b"\xc3a", & [ Bad (b"\xc3"), Ch ('a') ]),
+			("truncated three byte", b"\xe2\x82a", & [ Bad (b"\xe2\x82"), Ch ('a') ]),
+			("truncated four byte", b"\xf0\x9f\x98a", & [ Bad (b"\xf0\x9f\x98"), Ch ('a') ]),
+			("truncated then lead", b"\xe2\xc3\xa9", & [ Bad (b"\xe2"), Ch ('é') ]),
+			("truncated then escape", b"\xf0\x9f\x1b", & [ Bad (b"\xf0\x9f"), Ch ('\x1b') ]),
+		];
+		for & (name, bytes, expect) in cases {
+			assert_eq! (decode_all (bytes), expect, "{name}");
+		}
+	}
+
+	fn screen (rows: & [& str]) -> Screen {
+		let mut screen = Screen::new (rows.len (), 6);
+		for (row, text) in rows.iter ().enumerate () {
+			screen.move_to (row, 0);
+			screen.put_str (text);
+		}
+		screen
+	}
+
+	fn render_cells (prev: & [& str], rows: & [& str]) -> String {
+		let mut sgr_cache = SgrCache { colour_depth: ColourDepth::Ansi16, cache: HashMap::new () };
+		let mut out = Vec::new ();
+		Terminal::render_cells (& mut out, & screen (rows), Some (& screen (prev)), & mut sgr_cache);
+		String::from_utf8 (out).unwrap ()
+	}
+
+	#[ test ]
+	fn render_diff () {
+		let sgr = TextAttr::default ().sgr (ColourDepth::Ansi16);
+		assert_eq! (render_cells (& [ "abc", "def" ], & [ "abc", "def" ]), "");
+		assert_eq! (render_cells (& [ "abc", "def" ], & [ "axc", "def" ]), format! ("\x1b[1;2H{sgr}x"));
+		assert_eq! (render_cells (& [ "abc", "def" ], & [ "axc", "dyf" ]), format! ("\x1b[1;2H{sgr}x\x1b[2;2Hy"));
+		assert_eq! (render_cells (& [ "abcdef" ], & [ "axcxef" ]), format! ("\x1b[1;2H{sgr}xcx"));
+		assert_eq! (render_cells (& [ "abc" ], & [ "a" ]), format! ("\x1b[1;2H{sgr}\x1b[K"));
+		assert_eq! (render_cells (& [ "界b" ], & [ "xyb" ]), format! ("\x1b[1;1H{sgr}xy"));
+		assert_eq! (render_cells (& [ "ab" ], & [ "界" ]), format! ("\x1b[1;1H{sgr}界"));
+	}
+
+	#[ test ]
+	fn csi_u_keys () {
+		let cases: & [(& str, & [u32], Option <InputEvent>)] = & [
+			("enter", & [ 13 ], Some (InputEvent::Key (Key::Enter))),
+			("tab", & [ 9 ], Some (InputEvent::Key (Key::Tab))),
+			("escape", & [ 27 ], Some (InputEvent::Key (Key::Escape))),
+			("backspace", & [ 127, 1 ], Some (InputEvent::Key (Key::Backspace))),
+			("char", & [ 97 ], Some (InputEvent::Key (Key::Char ('a')))),
+			("shift enter", & [ 13, 2 ], Some (InputEvent::ShiftKey (Key::Enter))),
+			("alt tab", & [ 9, 3 ], Some (InputEvent::AltKey (Key::Tab))),
+			("alt shift escape", & [ 27, 4 ], Some (InputEvent::AltShiftKey (Key::Escape))),
+			("ctrl i", & [ 105, 5 ], Some (InputEvent::CtrlKey (Key::Char ('i')))),
+			("ctrl m", & [ 109, 5 ], Some (InputEvent::CtrlKey (Key::Char ('m')))),
+			("ctrl enter", & [ 13, 5 ], Some (InputEvent::CtrlKey (Key::Enter))),
+			("ctrl backspace", & [ 127, 5 ], Some (InputEvent::CtrlKey (Key::Backspace))),
+			("ctrl shift tab", & [ 9, 6 ], Some (InputEvent::CtrlShiftKey (Key::Tab))),
+			("ctrl alt char", & [ 120, 7 ], Some (InputEvent::CtrlAltKey (Key::Char ('x')))),
+			("caps and num lock", & [ 97, 1 + 0x40 + 0x80 + 4 ], Some (InputEvent::CtrlKey (Key::Char ('a')))),
+			("super", & [ 97, 9 ], None),
+			("no modifiers", & [ 97, 0 ], None),
+			("private use", & [ 57_441, 1 ], None),
+			("surrogate", & [ 0xd800 ], None),
+		];
+		for & (name, vals, ref expect) in cases {
+			assert_eq! (Terminal::decode_csi (vals, b'u'), * expect, "{name}");
+		}
+	}
+
+}